@@ -1,29 +1,500 @@
 use crate::error::Result;
-use serde::Deserialize;
-use std::process::Command;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tower_lsp::lsp_types::DiagnosticSeverity;
 
-#[derive(Clone, Debug, Deserialize)]
+/// Whether to keep a dmypy daemon running or to run mypy once per check.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DaemonOrOneShot {
+    #[default]
+    Daemon,
+    OneShot,
+}
+
+/// How log records are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DmypylsConfig {
-    pub dmypy_command: Vec<String>,
+    /// The command used to run dmypy. When unset, it is detected (see `resolve_command`).
+    #[serde(default)]
+    pub dmypy_command: Option<Vec<String>>,
+    #[serde(default)]
+    pub mode: DaemonOrOneShot,
+    /// The mypy command used in `one_shot` mode. Defaults to `dmypy_command` with `dmypy`
+    /// replaced by `mypy`.
+    #[serde(default)]
+    pub mypy_command: Option<Vec<String>>,
+    /// How long to wait for further save/change events on a file before checking it.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// How many times to retry a check that failed transiently, e.g. because the daemon was busy
+    /// starting up. Retries back off exponentially.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// How many dmypy or mypy checks may run at once. Further checks wait for a running one to
+    /// finish. Read at startup.
+    #[serde(default = "default_max_concurrent_checks")]
+    pub max_concurrent_checks: usize,
+    /// Also check files when they change in the editor, not only when they are saved.
+    #[serde(default)]
+    pub check_on_change: bool,
+    /// After a file is saved, check the whole project and re-publish the diagnostics of every file,
+    /// since the change may have introduced errors in the files that depend on it.
+    #[serde(default)]
+    pub recheck_dependents: bool,
+    /// Clear a file's diagnostics when the editor closes it.
+    #[serde(default = "default_true")]
+    pub clear_diagnostics_on_close: bool,
+    /// Check files opened from outside the project root with mypy, using the directory containing
+    /// each file as its root. Otherwise such files are skipped.
+    #[serde(default)]
+    pub check_files_outside_root: bool,
+    /// Extensions (without the leading dot) of the files that should be checked.
+    #[serde(default = "default_file_extensions")]
+    pub file_extensions: Vec<String>,
+    /// Also check files without an extension whose first line is a Python shebang, e.g.
+    /// `#!/usr/bin/env python3`.
+    #[serde(default)]
+    pub check_shebang_scripts: bool,
+    /// Maps a mypy error code (e.g. `union-attr`) or category (e.g. `note`) to the name of the
+    /// severity it should be reported with (`error`, `warning`, `information` or `hint`).
+    #[serde(default)]
+    pub severity_overrides: HashMap<String, String>,
+    /// The severity of notes (`error`, `warning`, `information` or `hint`). Defaults to `hint`.
+    /// A `note` entry in `severity_overrides` takes precedence.
+    #[serde(default = "default_note_severity")]
+    pub note_severity: String,
+    /// The `source` label shown with each diagnostic, e.g. to tell dmypyls apart from other
+    /// mypy-based tools.
+    #[serde(default = "default_diagnostic_source")]
+    pub diagnostic_source: String,
+    /// Shrink the end of each diagnostic's range back to the last non-whitespace character before
+    /// it, for end columns that mypy places in trailing whitespace.
+    #[serde(default)]
+    pub trim_diagnostic_ranges: bool,
+    /// After each check of a file, log a summary of its diagnostics (e.g. "2 errors, 1 warning")
+    /// to the client.
+    #[serde(default)]
+    pub report_summary: bool,
+    /// mypy error codes (e.g. `no-untyped-def`) whose diagnostics are not reported.
+    #[serde(default)]
+    pub ignore_codes: Vec<String>,
+    /// Directories, relative to the project root, to which checking is limited (e.g. `backend`).
+    /// When empty, every file is checked.
+    #[serde(default)]
+    pub include_paths: Vec<String>,
+    /// Regexes for files that aren't checked, matched against their path relative to the project
+    /// root. Defaults to the `exclude` setting of the mypy configuration.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// The most diagnostics reported for a single file, so that a broken file doesn't flood the
+    /// editor with cascading errors.
+    #[serde(default)]
+    pub max_diagnostics_per_file: Option<usize>,
+    /// Report a failed check that produced no diagnostics (e.g. due to a configuration error) as
+    /// a diagnostic at the top of the file, rather than showing the file as clean.
+    #[serde(default = "default_true")]
+    pub surface_daemon_errors: bool,
+    /// Show the types reported by `reveal_type(...)` on hover rather than as diagnostics.
+    #[serde(default)]
+    pub reveal_type_as_hint: bool,
+    /// The interpreter mypy resolves imports against. Defaults to the python in `$VIRTUAL_ENV`.
+    #[serde(default)]
+    pub python_executable: Option<PathBuf>,
+    /// The mypy configuration file to use, instead of searching upwards from the project root for
+    /// `mypy.ini`, `.mypy.ini`, `pyproject.toml` or `setup.cfg`. Relative to the project root.
+    #[serde(default)]
+    pub mypy_config: Option<PathBuf>,
+    /// The file through which dmypy finds its daemon. Defaults to a file under the XDG state
+    /// directory that is unique to the project root, so that projects don't share a daemon.
+    #[serde(default)]
+    pub status_file: Option<PathBuf>,
+    /// The directory dmypy and mypy are run from, relative to the project root unless absolute.
+    /// Defaults to the project root, whatever the server's own working directory.
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    /// Extra mypy flags (e.g. `--cache-fine-grained`), passed verbatim after `--` in `dmypy run`
+    /// and to mypy itself in `one_shot` mode.
+    #[serde(default)]
+    pub extra_run_args: Vec<String>,
+    /// Stop the daemon when no file has been checked or hovered for this many seconds. It is
+    /// restarted by the next check.
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Check the whole project once the server is initialized, publishing diagnostics for every
+    /// file rather than only the open ones.
+    #[serde(default)]
+    pub check_workspace_on_startup: bool,
+    /// The directories, relative to the project root, that module names are relative to (e.g. `src`
+    /// for a `src/` layout). The project root itself is used for files outside all of them.
+    #[serde(default)]
+    pub source_roots: Vec<PathBuf>,
+    /// Offer code actions that annotate functions using `dmypy suggest`, which can be slow.
+    #[serde(default)]
+    pub enable_suggest: bool,
+    /// Show the inferred types of unannotated variables as inlay hints.
+    #[serde(default = "default_true")]
+    pub enable_inlay_hints: bool,
+    /// Show types on hover.
+    #[serde(default = "default_true")]
+    pub enable_hover: bool,
+    /// Answer go-to-definition requests.
+    #[serde(default = "default_true")]
+    pub enable_definition: bool,
+    /// Complete attribute names after a `.`.
+    #[serde(default = "default_true")]
+    pub enable_completion: bool,
+    /// Where to write the log, instead of `dmypyls.log` in the XDG state directory.
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    /// The log level (e.g. `debug`). `RUST_LOG_LEVEL` takes precedence when set.
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// Whether to write the log as plain text or as JSON lines.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Reject configuration files with unknown keys, which are most likely typos.
+    #[serde(default = "default_true")]
+    pub strict_config: bool,
+}
+
+impl Default for DmypylsConfig {
+    /// The configuration used when no `dmypyls.yaml` exists: every field takes its default.
+    fn default() -> Self {
+        serde_yml::from_str("{}").unwrap()
+    }
+}
+
+fn default_debounce_ms() -> u64 {
+    200
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_max_concurrent_checks() -> usize {
+    4
+}
+
+fn default_note_severity() -> String {
+    "hint".to_string()
+}
+
+fn default_diagnostic_source() -> String {
+    "dmypy".to_string()
+}
+
+fn default_file_extensions() -> Vec<String> {
+    vec!["py".to_string(), "pyi".to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn build_command(terms: &[String]) -> Result<Command> {
+    let mut terms = terms.iter();
+    let mut cmd = Command::new(expand_env_vars(
+        terms.next().ok_or_else(crate::error::Error::no_command)?,
+    ));
+    for term in terms {
+        cmd.arg(expand_env_vars(term));
+    }
+    Ok(cmd)
+}
+
+/// Looks for dmypy in the usual virtualenv locations: `.venv` and `venv` under `dir`, then
+/// `$VIRTUAL_ENV`.
+fn find_venv_dmypy(dir: &Path, virtual_env: Option<&str>) -> Option<PathBuf> {
+    let executable = if cfg!(windows) {
+        Path::new("Scripts").join("dmypy.exe")
+    } else {
+        Path::new("bin").join("dmypy")
+    };
+    [dir.join(".venv"), dir.join("venv")]
+        .into_iter()
+        .chain(virtual_env.map(PathBuf::from))
+        .map(|venv| venv.join(&executable))
+        .find(|candidate| candidate.is_file())
 }
 
 impl DmypylsConfig {
-    pub fn command(&self) -> Result<Command> {
-        let mut terms = self.dmypy_command.iter();
-        let mut cmd = Command::new(
-            terms
-                .next()
-                .ok_or("No dmypy command found (see dmypyls.yaml in README.md)")?,
-        );
-        for term in terms {
-            cmd.arg(term);
+    /// The configured `dmypy_command`, or else dmypy from a virtualenv in `root_dir` or
+    /// `$VIRTUAL_ENV`, falling back to `dmypy` on the `PATH`.
+    pub fn resolve_command(&self, root_dir: &Path) -> Vec<String> {
+        if let Some(dmypy_command) = &self.dmypy_command {
+            return dmypy_command.clone();
+        }
+        let virtual_env = std::env::var("VIRTUAL_ENV").ok();
+        match find_venv_dmypy(root_dir, virtual_env.as_deref()) {
+            Some(dmypy) => vec![dmypy.to_string_lossy().into_owned()],
+            None => vec!["dmypy".to_string()],
         }
+    }
+
+    pub fn command(&self, root_dir: &Path) -> Result<Command> {
+        build_command(&self.resolve_command(root_dir))
+    }
+
+    /// A dmypy command for the project in `root_dir` that talks to its daemon, through the
+    /// configured status file or else `default_status_file`.
+    pub fn daemon_command(&self, root_dir: &Path, default_status_file: &Path) -> Result<Command> {
+        let mut cmd = self.command(root_dir)?;
+        let status_file = self
+            .status_file()
+            .unwrap_or_else(|| default_status_file.to_path_buf());
+        cmd.arg("--status-file").arg(status_file);
         Ok(cmd)
     }
+
+    pub fn mypy_command(&self, root_dir: &Path) -> Result<Command> {
+        match &self.mypy_command {
+            Some(mypy_command) => build_command(mypy_command),
+            None => build_command(&derive_mypy_command(&self.resolve_command(root_dir))),
+        }
+    }
+
+    pub fn uses_daemon(&self) -> bool {
+        self.mode == DaemonOrOneShot::Daemon
+    }
+
+    /// The configured `mypy_config`, with environment variables expanded.
+    pub fn mypy_config(&self) -> Option<PathBuf> {
+        self.mypy_config
+            .as_ref()
+            .map(|mypy_config| PathBuf::from(expand_env_vars(&mypy_config.to_string_lossy())))
+    }
+
+    /// The configured `python_executable`, or else the interpreter of the active virtualenv.
+    pub fn python_executable(&self) -> Option<PathBuf> {
+        if let Some(python_executable) = &self.python_executable {
+            return Some(PathBuf::from(expand_env_vars(
+                &python_executable.to_string_lossy(),
+            )));
+        }
+        let virtual_env = PathBuf::from(std::env::var("VIRTUAL_ENV").ok()?);
+        let python = if cfg!(windows) {
+            virtual_env.join("Scripts").join("python.exe")
+        } else {
+            virtual_env.join("bin").join("python")
+        };
+        python.is_file().then_some(python)
+    }
+
+    /// The configured `status_file`, with environment variables expanded.
+    pub fn status_file(&self) -> Option<PathBuf> {
+        self.status_file
+            .as_ref()
+            .map(|status_file| PathBuf::from(expand_env_vars(&status_file.to_string_lossy())))
+    }
+
+    /// The configured `working_dir`, with environment variables expanded. A relative path is
+    /// resolved against the project root.
+    pub fn working_dir(&self) -> Option<PathBuf> {
+        self.working_dir
+            .as_ref()
+            .map(|working_dir| PathBuf::from(expand_env_vars(&working_dir.to_string_lossy())))
+    }
+
+    /// The configured log file, with environment variables expanded.
+    pub fn log_file(&self) -> Option<PathBuf> {
+        self.log_file
+            .as_ref()
+            .map(|log_file| PathBuf::from(expand_env_vars(&log_file.to_string_lossy())))
+    }
+
+    /// Looks up the severity override for a diagnostic, preferring its error code over its
+    /// category.
+    pub fn severity_override(
+        &self,
+        code: Option<&str>,
+        category: &str,
+    ) -> Option<DiagnosticSeverity> {
+        code.and_then(|code| self.severity_overrides.get(code))
+            .or_else(|| self.severity_overrides.get(category))
+            .and_then(|name| parse_severity(name))
+    }
+
+    /// The severity notes are reported with, see `note_severity`.
+    pub fn note_severity(&self) -> DiagnosticSeverity {
+        parse_severity(&self.note_severity).unwrap_or(DiagnosticSeverity::HINT)
+    }
+
+    /// Whether diagnostics with the given code should be dropped.
+    pub fn is_ignored_code(&self, code: &str) -> bool {
+        self.ignore_codes.iter().any(|ignored| ignored == code)
+    }
+
+    /// Whether a path relative to the project root is under one of `include_paths`, if any are
+    /// configured.
+    pub fn is_included(&self, path: &Path) -> bool {
+        self.include_paths.is_empty()
+            || self
+                .include_paths
+                .iter()
+                .any(|include_path| path.starts_with(include_path))
+    }
+
+    /// Whether files with the given extension should be checked. Case-insensitive.
+    pub fn is_checked_extension(&self, extension: &str) -> bool {
+        self.file_extensions
+            .iter()
+            .any(|candidate| candidate.eq_ignore_ascii_case(extension))
+    }
+}
+
+/// Replaces `dmypy` with `mypy` in the last term of a dmypy command, e.g. `.venv/bin/dmypy` or
+/// `uv run dmypy`.
+fn derive_mypy_command(dmypy_command: &[String]) -> Vec<String> {
+    let mut terms = dmypy_command.to_vec();
+    if let Some(last) = terms.last_mut() {
+        if let Some(prefix) = last.strip_suffix("dmypy") {
+            *last = format!("{prefix}mypy");
+        } else if let Some(prefix) = last.strip_suffix("dmypy.exe") {
+            *last = format!("{prefix}mypy.exe");
+        }
+    }
+    terms
+}
+
+/// Expands `$VAR` and `${VAR}` references using the process environment. Unknown variables expand
+/// to an empty string (with a warning in the log).
+pub fn expand_env_vars(value: &str) -> String {
+    let re = Regex::new(r"\$(?:\{(\w+)\}|(\w+))").unwrap();
+    re.replace_all(value, |caps: &Captures| {
+        let name = caps.get(1).or_else(|| caps.get(2)).unwrap().as_str();
+        std::env::var(name).unwrap_or_else(|_| {
+            log::warn!("environment variable ${name} is not set (referenced in {value:?})");
+            String::new()
+        })
+    })
+    .into_owned()
+}
+
+/// Parses a severity name as used in the configuration file. Case-insensitive.
+pub fn parse_severity(name: &str) -> Option<DiagnosticSeverity> {
+    match name.to_ascii_lowercase().as_str() {
+        "error" => Some(DiagnosticSeverity::ERROR),
+        "warning" => Some(DiagnosticSeverity::WARNING),
+        "information" => Some(DiagnosticSeverity::INFORMATION),
+        "hint" => Some(DiagnosticSeverity::HINT),
+        _ => None,
+    }
+}
+
+impl DmypylsConfig {
+    /// Checks the values serde can't check by itself.
+    fn validate(&self) -> Result<()> {
+        for (key, name) in &self.severity_overrides {
+            if parse_severity(name).is_none() {
+                return Err(format!(
+                    "invalid severity {name:?} for {key:?} in severity_overrides \
+                     (expected one of error, warning, information, hint)"
+                )
+                .into());
+            }
+        }
+        if parse_severity(&self.note_severity).is_none() {
+            return Err(format!(
+                "invalid note_severity {:?} (expected one of error, warning, information, hint)",
+                self.note_severity
+            )
+            .into());
+        }
+        if self.max_concurrent_checks == 0 {
+            return Err("max_concurrent_checks must be at least 1".into());
+        }
+        if let Some(log_level) = &self.log_level {
+            log_level.parse::<log::LevelFilter>()?;
+        }
+        for pattern in &self.exclude {
+            Regex::new(pattern)?;
+        }
+        Ok(())
+    }
+
+    /// Overlays (possibly partial) settings sent by the client, e.g. via
+    /// `workspace/didChangeConfiguration`, onto this configuration. Settings may be nested under a
+    /// `dmypyls` key. Returns the new configuration and the names of the settings that changed.
+    pub fn apply_settings(&self, settings: &Value) -> Result<(DmypylsConfig, Vec<String>)> {
+        let settings = settings.get("dmypyls").unwrap_or(settings);
+        let Value::Object(settings) = settings else {
+            return Err(format!("settings are not an object: {settings}").into());
+        };
+        let Value::Object(mut merged) = serde_json::to_value(self)? else {
+            unreachable!("configuration serializes to an object");
+        };
+        let mut changed = Vec::new();
+        for (key, value) in settings {
+            if merged.get(key) != Some(value) {
+                changed.push(key.clone());
+            }
+            merged.insert(key.clone(), value.clone());
+        }
+        let config: DmypylsConfig = serde_json::from_value(Value::Object(merged))?;
+        config.validate()?;
+        Ok((config, changed))
+    }
+}
+
+/// Fails on the first key of a configuration file that isn't a configuration option.
+fn check_unknown_keys<'a>(keys: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    let Value::Object(known) = serde_json::to_value(DmypylsConfig::default())? else {
+        unreachable!("configuration serializes to an object");
+    };
+    for key in keys {
+        if !known.contains_key(key) {
+            return Err(format!(
+                "unknown configuration key {key:?} (set strict_config: false to allow unknown keys)"
+            )
+            .into());
+        }
+    }
+    Ok(())
 }
 
 pub fn parse_config(content: &str) -> Result<DmypylsConfig> {
-    Ok(serde_yml::from_str(content)?)
+    let config: DmypylsConfig = serde_yml::from_str(content)?;
+    if config.strict_config {
+        if let serde_yml::Value::Mapping(keys) = serde_yml::from_str(content)? {
+            check_unknown_keys(keys.keys().map(|key| key.as_str().unwrap_or_default()))?;
+        }
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+/// Parses the `[tool.dmypyls]` table of a `pyproject.toml`. Returns `None` if there is no such
+/// table.
+pub fn parse_config_toml(content: &str) -> Result<Option<DmypylsConfig>> {
+    let mut pyproject: toml::Table = toml::from_str(content)?;
+    let Some(toml::Value::Table(table)) = pyproject.remove("tool").and_then(|tool| match tool {
+        toml::Value::Table(mut tool) => tool.remove("dmypyls"),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+    let keys: Vec<String> = table.keys().cloned().collect();
+    let config: DmypylsConfig = toml::Value::Table(table).try_into()?;
+    if config.strict_config {
+        check_unknown_keys(keys.iter().map(String::as_str))?;
+    }
+    config.validate()?;
+    Ok(Some(config))
 }
 
 #[test]
@@ -31,3 +502,280 @@ fn test_parse_config() {
     let content = r#"{ "dmypy_command": ["dmypy"] }"#;
     assert!(parse_config(content).is_ok());
 }
+
+#[test]
+fn test_parse_config_unknown_keys() {
+    let error = parse_config("dmypy_comand: [dmypy]\n").unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("unknown configuration key \"dmypy_comand\""));
+    let config = parse_config("dmypy_comand: [dmypy]\nstrict_config: false\n").unwrap();
+    assert_eq!(config.dmypy_command, None);
+    assert!(parse_config("dmypy_command: [dmypy]\nstrict_config: true\n").is_ok());
+}
+
+#[test]
+fn test_parse_config_toml() {
+    let content = r#"
+[project]
+name = "example"
+
+[tool.dmypyls]
+dmypy_command = ["dmypy"]
+debounce_ms = 50
+"#;
+    let config = parse_config_toml(content).unwrap().unwrap();
+    assert_eq!(config.dmypy_command, Some(vec!["dmypy".to_string()]));
+    assert_eq!(config.debounce_ms, 50);
+    assert!(parse_config_toml("[tool.mypy]\nstrict = true\n")
+        .unwrap()
+        .is_none());
+    let error = parse_config_toml("[tool.dmypyls]\ndmypy_comand = [\"dmypy\"]\n").unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("unknown configuration key \"dmypy_comand\""));
+    assert!(parse_config_toml("[tool.dmypyls\n").is_err());
+}
+
+#[test]
+fn test_default_config() {
+    let config = DmypylsConfig::default();
+    assert_eq!(config.dmypy_command, None);
+    assert_eq!(config.debounce_ms, 200);
+}
+
+#[test]
+fn test_parse_config_debounce_ms() {
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    assert_eq!(config.debounce_ms, 200);
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"], "debounce_ms": 50 }"#).unwrap();
+    assert_eq!(config.debounce_ms, 50);
+}
+
+#[test]
+fn test_parse_config_severity_overrides() {
+    let config = parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "severity_overrides": { "note": "hint", "union-attr": "Warning" } }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.severity_override(Some("union-attr"), "error"),
+        Some(DiagnosticSeverity::WARNING)
+    );
+    assert_eq!(
+        config.severity_override(None, "note"),
+        Some(DiagnosticSeverity::HINT)
+    );
+    assert_eq!(config.severity_override(Some("misc"), "error"), None);
+    assert!(parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "severity_overrides": { "note": "loud" } }"#
+    )
+    .is_err());
+}
+
+#[test]
+fn test_parse_config_note_severity() {
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    assert_eq!(config.note_severity, "hint");
+    assert_eq!(config.note_severity(), DiagnosticSeverity::HINT);
+    let config =
+        parse_config(r#"{ "dmypy_command": ["dmypy"], "note_severity": "Information" }"#).unwrap();
+    assert_eq!(config.note_severity(), DiagnosticSeverity::INFORMATION);
+    assert!(parse_config(r#"{ "dmypy_command": ["dmypy"], "note_severity": "quiet" }"#).is_err());
+}
+
+#[test]
+fn test_parse_config_logging() {
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    assert_eq!(config.log_file(), None);
+    assert_eq!(config.log_level, None);
+    std::env::set_var("DMYPYLS_TEST_LOG_DIR", "/tmp/logs");
+    let config = parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "log_file": "$DMYPYLS_TEST_LOG_DIR/dmypyls.log", "log_level": "debug" }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.log_file(),
+        Some(PathBuf::from("/tmp/logs/dmypyls.log"))
+    );
+    assert_eq!(config.log_level.as_deref(), Some("debug"));
+    assert_eq!(config.log_format, LogFormat::Text);
+    assert!(parse_config(r#"{ "dmypy_command": ["dmypy"], "log_level": "chatty" }"#).is_err());
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"], "log_format": "json" }"#).unwrap();
+    assert_eq!(config.log_format, LogFormat::Json);
+    assert!(parse_config(r#"{ "dmypy_command": ["dmypy"], "log_format": "xml" }"#).is_err());
+}
+
+#[test]
+fn test_parse_config_mode() {
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    assert_eq!(config.mode, DaemonOrOneShot::Daemon);
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"], "mode": "one_shot" }"#).unwrap();
+    assert!(!config.uses_daemon());
+}
+
+#[test]
+fn test_derive_mypy_command() {
+    let terms = |terms: &[&str]| terms.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        derive_mypy_command(&terms(&[".venv/bin/dmypy"])),
+        terms(&[".venv/bin/mypy"])
+    );
+    assert_eq!(
+        derive_mypy_command(&terms(&["uv", "run", "dmypy"])),
+        terms(&["uv", "run", "mypy"])
+    );
+}
+
+#[test]
+fn test_find_venv_dmypy() {
+    let dir = crate::test_dir("venv");
+    let executable = if cfg!(windows) {
+        Path::new("Scripts").join("dmypy.exe")
+    } else {
+        Path::new("bin").join("dmypy")
+    };
+    assert_eq!(find_venv_dmypy(&dir, None), None);
+
+    let virtual_env = dir.join("active");
+    std::fs::create_dir_all(virtual_env.join(&executable).parent().unwrap()).unwrap();
+    std::fs::write(virtual_env.join(&executable), "").unwrap();
+    assert_eq!(
+        find_venv_dmypy(&dir, virtual_env.to_str()),
+        Some(virtual_env.join(&executable))
+    );
+
+    let venv = dir.join(".venv");
+    std::fs::create_dir_all(venv.join(&executable).parent().unwrap()).unwrap();
+    std::fs::write(venv.join(&executable), "").unwrap();
+    assert_eq!(
+        find_venv_dmypy(&dir, virtual_env.to_str()),
+        Some(venv.join(&executable))
+    );
+    assert_eq!(
+        parse_config("{}").unwrap().resolve_command(&dir),
+        [venv.join(&executable).to_string_lossy()]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resolve_command_configured() {
+    let config = parse_config(r#"{ "dmypy_command": ["uv", "run", "dmypy"] }"#).unwrap();
+    assert_eq!(
+        config.resolve_command(Path::new("/project")),
+        ["uv", "run", "dmypy"]
+    );
+}
+
+#[test]
+fn test_python_executable_configured() {
+    let config = parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "python_executable": "/opt/python/bin/python3" }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.python_executable(),
+        Some(PathBuf::from("/opt/python/bin/python3"))
+    );
+}
+
+#[test]
+fn test_daemon_command_status_file() {
+    let default_status_file = Path::new("/state/dmypyls/daemon-0123.json");
+    let config = parse_config(r#"{ "dmypy_command": ["uv", "run", "dmypy"] }"#).unwrap();
+    for subcommand in ["status", "run", "check", "stop"] {
+        let mut cmd = config
+            .daemon_command(Path::new("/project"), default_status_file)
+            .unwrap();
+        cmd.arg(subcommand);
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "run",
+                "dmypy",
+                "--status-file",
+                "/state/dmypyls/daemon-0123.json",
+                subcommand
+            ]
+        );
+    }
+    let config =
+        parse_config(r#"{ "dmypy_command": ["dmypy"], "status_file": "/project/.dmypy.json" }"#)
+            .unwrap();
+    let cmd = config
+        .daemon_command(Path::new("/project"), default_status_file)
+        .unwrap();
+    let args: Vec<_> = cmd.as_std().get_args().collect();
+    assert_eq!(args, ["--status-file", "/project/.dmypy.json"]);
+}
+
+#[test]
+fn test_apply_settings() {
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"], "debounce_ms": 50 }"#).unwrap();
+    let settings = serde_json::json!({
+        "dmypyls": { "dmypy_command": ["uv", "run", "dmypy"], "debounce_ms": 50 }
+    });
+    let (config, changed) = config.apply_settings(&settings).unwrap();
+    assert_eq!(changed, vec!["dmypy_command".to_string()]);
+    assert_eq!(
+        config.resolve_command(Path::new("/project")),
+        ["uv", "run", "dmypy"]
+    );
+    assert_eq!(config.debounce_ms, 50);
+    assert!(config
+        .apply_settings(&serde_json::json!({ "log_level": "chatty" }))
+        .is_err());
+}
+
+#[test]
+fn test_is_checked_extension() {
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    assert!(config.is_checked_extension("py"));
+    assert!(config.is_checked_extension("pyi"));
+    assert!(config.is_checked_extension("PY"));
+    assert!(!config.is_checked_extension("txt"));
+    assert!(!config.is_checked_extension(""));
+}
+
+#[test]
+fn test_is_included() {
+    let config = parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    assert!(config.is_included(Path::new("anything/at/all.py")));
+    let config = parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "include_paths": ["backend", "scripts/", "tools/py"] }"#,
+    )
+    .unwrap();
+    assert!(config.is_included(Path::new("backend/app.py")));
+    assert!(config.is_included(Path::new("backend/api/v1/views.py")));
+    assert!(config.is_included(Path::new("scripts/deploy.py")));
+    assert!(config.is_included(Path::new("tools/py/lint.py")));
+    assert!(!config.is_included(Path::new("tools/js/lint.py")));
+    assert!(!config.is_included(Path::new("backend2/app.py")));
+    assert!(!config.is_included(Path::new("frontend/backend/app.py")));
+    assert!(!config.is_included(Path::new("setup.py")));
+}
+
+#[test]
+fn test_expand_env_vars() {
+    std::env::set_var("DMYPYLS_TEST_VENV", "/tmp/venv");
+    assert_eq!(
+        expand_env_vars("$DMYPYLS_TEST_VENV/bin/dmypy"),
+        "/tmp/venv/bin/dmypy"
+    );
+    assert_eq!(
+        expand_env_vars("${DMYPYLS_TEST_VENV}/bin/dmypy"),
+        "/tmp/venv/bin/dmypy"
+    );
+    assert_eq!(expand_env_vars("dmypy"), "dmypy");
+}
+
+#[test]
+fn test_expand_env_vars_missing() {
+    std::env::remove_var("DMYPYLS_TEST_MISSING");
+    assert_eq!(
+        expand_env_vars("${DMYPYLS_TEST_MISSING}/bin/dmypy"),
+        "/bin/dmypy"
+    );
+}
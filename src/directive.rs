@@ -0,0 +1,48 @@
+use regex::Regex;
+
+/// How many lines at the top of a file are searched for `# dmypyls:` directives.
+const DIRECTIVE_LINES: usize = 10;
+
+/// Whether the top of a file turns checking off with `# dmypyls: disable`. A later
+/// `# dmypyls: enable` turns it back on, so the last directive wins.
+pub(crate) fn is_disabled(text: &str) -> bool {
+    let re = Regex::new(r"^\s*#\s*dmypyls:\s*(?P<directive>disable|enable)\s*$").unwrap();
+    text.lines()
+        .take(DIRECTIVE_LINES)
+        .filter_map(|line| re.captures(line))
+        .last()
+        .is_some_and(|caps| &caps["directive"] == "disable")
+}
+
+/// Whether the first line of a file is a shebang that runs Python, e.g. `#!/usr/bin/env python3`.
+pub(crate) fn is_python_shebang(text: &str) -> bool {
+    let re = Regex::new(r"^#!.*[/\s]python[0-9.]*(\s|$)").unwrap();
+    text.lines().next().is_some_and(|line| re.is_match(line))
+}
+
+#[test]
+fn test_is_python_shebang() {
+    assert!(is_python_shebang("#!/usr/bin/env python3\nimport os\n"));
+    assert!(is_python_shebang("#!/usr/bin/python3.12 -u\n"));
+    assert!(is_python_shebang("#!/usr/bin/env -S python -O\n"));
+    assert!(!is_python_shebang("#!/bin/sh\necho python\n"));
+    assert!(!is_python_shebang("#!/usr/bin/env pythonista\n"));
+    assert!(!is_python_shebang("import os\n#!/usr/bin/env python3\n"));
+    assert!(!is_python_shebang(""));
+}
+
+#[test]
+fn test_is_disabled() {
+    assert!(!is_disabled("import os\n"));
+    assert!(is_disabled("# dmypyls: disable\nimport os\n"));
+    assert!(is_disabled(
+        "#!/usr/bin/env python3\n#dmypyls:disable\nimport os\n"
+    ));
+    assert!(!is_disabled(
+        "# dmypyls: disable\n# dmypyls: enable\nimport os\n"
+    ));
+    assert!(!is_disabled("x = 1  # dmypyls: disable\n"));
+    assert!(!is_disabled("# dmypyls: disabled\n"));
+    let late = format!("{}# dmypyls: disable\n", "x = 1\n".repeat(DIRECTIVE_LINES));
+    assert!(!is_disabled(&late));
+}
@@ -18,18 +18,63 @@ pub trait Context<T> {
     }
 }
 
-impl<T, E: std::error::Error> Context<T> for std::result::Result<T, E> {
+impl<T, E: std::error::Error + Send + Sync + 'static> Context<T> for std::result::Result<T, E> {
     #[track_caller]
     #[inline]
     fn context(self, context: &str) -> Result<T> {
-        self.map_err(|e| Error::new(format!("{context}: {e}")))
+        self.map_err(|e| {
+            let kind = ErrorKind::of(&e);
+            Error::new(format!("{context}: {e}"))
+                .with_kind(kind)
+                .with_source(e)
+        })
+    }
+}
+
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+/// What kind of failure an `Error` is, so that callers can decide whether to retry, restart the
+/// daemon or tell the user without matching on messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// No dmypy command is configured or could be found.
+    NoCommand,
+    /// Running a command or reading a file failed.
+    Io,
+    /// Configuration or command output could not be parsed.
+    Parse,
+    /// The dmypy daemon isn't running and could not be started.
+    DaemonNotRunning,
+    Other,
+}
+
+impl ErrorKind {
+    /// The kind of an error from elsewhere, looking through `Error`s wrapped with `context`.
+    fn of(error: &(dyn std::error::Error + 'static)) -> Self {
+        if let Some(error) = error.downcast_ref::<Error>() {
+            return error.kind;
+        }
+        if error.is::<std::io::Error>() {
+            return ErrorKind::Io;
+        }
+        if error.is::<serde_json::Error>()
+            || error.is::<serde_yml::Error>()
+            || error.is::<toml::de::Error>()
+            || error.is::<regex::Error>()
+        {
+            return ErrorKind::Parse;
+        }
+        ErrorKind::Other
     }
 }
 
 #[derive(Debug)]
 pub struct Error {
     message: String,
+    kind: ErrorKind,
     location: &'static Location<'static>,
+    /// The underlying error, if any, for walking the chain of causes.
+    source: Option<Source>,
 }
 
 impl Error {
@@ -37,12 +82,35 @@ impl Error {
     pub fn new(message: String) -> Self {
         Self {
             message,
+            kind: ErrorKind::Other,
             location: Location::caller(),
+            source: None,
         }
     }
+
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    fn with_source(mut self, source: impl Into<Source>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
     #[track_caller]
     pub fn no_command() -> Self {
-        Self::new("No dmypy command found".to_string())
+        Self::new("No dmypy command found (see dmypyls.yaml in README.md)".to_string())
+            .with_kind(ErrorKind::NoCommand)
+    }
+
+    #[track_caller]
+    pub fn daemon_not_running(message: String) -> Self {
+        Self::new(message).with_kind(ErrorKind::DaemonNotRunning)
     }
 }
 
@@ -51,7 +119,10 @@ impl From<log::ParseLevelError> for Error {
     fn from(error: log::ParseLevelError) -> Self {
         Self {
             message: format!("log level error: {error:?}"),
+            kind: ErrorKind::Parse,
             location: Location::caller(),
+            // `ParseLevelError` only implements `std::error::Error` with log's `std` feature.
+            source: None,
         }
     }
 }
@@ -73,14 +144,22 @@ impl std::fmt::Display for Error {
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
 
 impl From<Box<dyn std::error::Error>> for Error {
     #[track_caller]
     fn from(error: Box<dyn std::error::Error>) -> Self {
         Self {
             message: format!("dyn error: {error:?}"),
+            kind: ErrorKind::Other,
             location: Location::caller(),
+            source: None,
         }
     }
 }
@@ -90,7 +169,9 @@ impl From<regex::Error> for Error {
     fn from(error: regex::Error) -> Self {
         Self {
             message: format!("regex error: {error:?}"),
+            kind: ErrorKind::Parse,
             location: Location::caller(),
+            source: Some(Box::new(error)),
         }
     }
 }
@@ -100,7 +181,9 @@ impl From<serde_yml::Error> for Error {
     fn from(error: serde_yml::Error) -> Self {
         Self {
             message: format!("yaml error: {error:?}"),
+            kind: ErrorKind::Parse,
             location: Location::caller(),
+            source: Some(Box::new(error)),
         }
     }
 }
@@ -109,7 +192,9 @@ impl From<serde_json::Error> for Error {
     fn from(error: serde_json::Error) -> Self {
         Self {
             message: format!("json error: {error:?}"),
+            kind: ErrorKind::Parse,
             location: Location::caller(),
+            source: Some(Box::new(error)),
         }
     }
 }
@@ -118,29 +203,33 @@ impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Self {
             message: format!("io error: {error:?}"),
+            kind: ErrorKind::Io,
             location: Location::caller(),
+            source: Some(Box::new(error)),
         }
     }
 }
 
-/*
 impl From<toml::de::Error> for Error {
     #[track_caller]
     fn from(error: toml::de::Error) -> Self {
         Self {
             message: format!("toml error: {error:?}"),
+            kind: ErrorKind::Parse,
             location: Location::caller(),
+            source: Some(Box::new(error)),
         }
     }
 }
-*/
 
 impl From<String> for Error {
     #[track_caller]
     fn from(error: String) -> Self {
         Self {
             message: format!("error: {error}"),
+            kind: ErrorKind::Other,
             location: Location::caller(),
+            source: None,
         }
     }
 }
@@ -150,7 +239,46 @@ impl From<&str> for Error {
     fn from(error: &str) -> Self {
         Self {
             message: format!("error: {error}"),
+            kind: ErrorKind::Other,
             location: Location::caller(),
+            source: None,
         }
     }
 }
+
+#[test]
+fn test_error_source() {
+    use std::error::Error as _;
+    let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "no dmypy");
+    let error = Err::<(), _>(io_error)
+        .context("Failed to run dmypy")
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("Failed to run dmypy: no dmypy [location="));
+    let source = error.source().unwrap();
+    assert_eq!(source.to_string(), "no dmypy");
+    assert!(source.downcast_ref::<std::io::Error>().is_some());
+    assert!(Error::from("plain").source().is_none());
+}
+
+#[test]
+fn test_error_kind() {
+    let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "no dmypy");
+    assert_eq!(Error::from(io_error).kind(), ErrorKind::Io);
+    assert_eq!(Error::no_command().kind(), ErrorKind::NoCommand);
+    assert_eq!(Error::from("plain").kind(), ErrorKind::Other);
+    let json_error = serde_json::from_str::<u32>("x").unwrap_err();
+    assert_eq!(Error::from(json_error).kind(), ErrorKind::Parse);
+    // `context` keeps the kind of the error it wraps.
+    let wrapped = Err::<(), _>(Error::no_command())
+        .context("Failed to check")
+        .unwrap_err();
+    assert_eq!(wrapped.kind(), ErrorKind::NoCommand);
+    let io_error = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+    let wrapped = Err::<(), _>(io_error).context("Failed to run").unwrap_err();
+    assert_eq!(wrapped.kind(), ErrorKind::Io);
+    let error = Error::daemon_not_running("dmypy run failed".to_string());
+    assert_eq!(error.kind(), ErrorKind::DaemonNotRunning);
+    assert!(error.to_string().starts_with("dmypy run failed [location="));
+}
@@ -0,0 +1,137 @@
+use tower_lsp::lsp_types::{DiagnosticTag, Url};
+
+/// Error codes documented in mypy's list of error codes that are enabled by default.
+const DEFAULT_ERROR_CODES: &[&str] = &[
+    "abstract",
+    "annotation-unchecked",
+    "arg-type",
+    "assert-type",
+    "assignment",
+    "attr-defined",
+    "await-not-async",
+    "call-arg",
+    "call-overload",
+    "dict-item",
+    "empty-body",
+    "exit-return",
+    "func-returns-value",
+    "has-type",
+    "import",
+    "import-not-found",
+    "import-untyped",
+    "index",
+    "list-item",
+    "literal-required",
+    "method-assign",
+    "misc",
+    "name-defined",
+    "name-match",
+    "narrowed-type-not-subtype",
+    "no-overload-impl",
+    "no-redef",
+    "operator",
+    "override",
+    "prop-decorator",
+    "return",
+    "return-value",
+    "safe-super",
+    "str-bytes-safe",
+    "str-format",
+    "syntax",
+    "top-level-await",
+    "truthy-function",
+    "type-abstract",
+    "type-var",
+    "typeddict-item",
+    "typeddict-readonly-mutated",
+    "typeddict-unknown-key",
+    "union-attr",
+    "unused-coroutine",
+    "used-before-def",
+    "valid-newtype",
+    "valid-type",
+    "var-annotated",
+];
+
+/// Error codes for optional checks, which mypy documents on a separate page.
+const OPTIONAL_ERROR_CODES: &[&str] = &[
+    "comparison-overlap",
+    "deprecated",
+    "exhaustive-match",
+    "explicit-any",
+    "explicit-override",
+    "ignore-without-code",
+    "mutable-override",
+    "no-any-return",
+    "no-any-unimported",
+    "no-untyped-call",
+    "no-untyped-def",
+    "possibly-undefined",
+    "redundant-cast",
+    "redundant-expr",
+    "redundant-self",
+    "truthy-bool",
+    "truthy-iterable",
+    "type-arg",
+    "unimported-reveal",
+    "unreachable",
+    "unused-awaitable",
+    "unused-ignore",
+];
+
+/// The page of mypy's documentation describing `code`, or `None` if it isn't a known mypy error
+/// code (e.g. one added by a plugin).
+pub(crate) fn documentation_url(code: &str) -> Option<Url> {
+    let page = if DEFAULT_ERROR_CODES.contains(&code) {
+        "error_code_list.html"
+    } else if OPTIONAL_ERROR_CODES.contains(&code) {
+        "error_code_list2.html"
+    } else {
+        return None;
+    };
+    Url::parse(&format!(
+        "https://mypy.readthedocs.io/en/stable/{page}#code-{code}"
+    ))
+    .ok()
+}
+
+/// Tags that let editors render e.g. unused ignores faded out and deprecated names struck through.
+pub(crate) fn diagnostic_tags(code: &str) -> Option<Vec<DiagnosticTag>> {
+    match code {
+        "unused-ignore" | "redundant-cast" => Some(vec![DiagnosticTag::UNNECESSARY]),
+        "deprecated" => Some(vec![DiagnosticTag::DEPRECATED]),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_documentation_url() {
+    assert_eq!(
+        documentation_url("assignment").map(String::from).as_deref(),
+        Some("https://mypy.readthedocs.io/en/stable/error_code_list.html#code-assignment")
+    );
+    assert_eq!(
+        documentation_url("unused-ignore")
+            .map(String::from)
+            .as_deref(),
+        Some("https://mypy.readthedocs.io/en/stable/error_code_list2.html#code-unused-ignore")
+    );
+    assert_eq!(documentation_url("my-plugin-code"), None);
+}
+
+#[test]
+fn test_diagnostic_tags() {
+    assert_eq!(
+        diagnostic_tags("unused-ignore"),
+        Some(vec![DiagnosticTag::UNNECESSARY])
+    );
+    assert_eq!(
+        diagnostic_tags("redundant-cast"),
+        Some(vec![DiagnosticTag::UNNECESSARY])
+    );
+    assert_eq!(
+        diagnostic_tags("deprecated"),
+        Some(vec![DiagnosticTag::DEPRECATED])
+    );
+    assert_eq!(diagnostic_tags("assignment"), None);
+}
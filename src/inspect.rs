@@ -0,0 +1,548 @@
+use crate::error::{Error, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tower_lsp::lsp_types::{Position, Range};
+
+/// A single inspection reported by `dmypy inspect`, e.g. the inferred type of one expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Inspection {
+    /// The span of the inspected expression, converted to 0-based LSP coordinates.
+    pub(crate) range: Option<Range>,
+    pub(crate) value: String,
+}
+
+impl Inspection {
+    fn covers(&self, position: Position) -> bool {
+        self.range
+            .is_some_and(|range| range.start <= position && position <= range.end)
+    }
+}
+
+/// Parses a 1-based `line:column:end_line:end_column` span as printed by `--include-span`.
+fn parse_span(span: &str) -> Option<Range> {
+    let coordinates: Vec<u32> = span
+        .split(':')
+        .map(|term| term.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    let [start_line, start_column, end_line, end_column] = coordinates[..] else {
+        return None;
+    };
+    Some(Range {
+        start: Position {
+            line: start_line.saturating_sub(1),
+            character: start_column.saturating_sub(1),
+        },
+        end: Position {
+            line: end_line.saturating_sub(1),
+            character: end_column.saturating_sub(1),
+        },
+    })
+}
+
+fn parse_span_value(span: &Value) -> Option<Range> {
+    match span {
+        Value::String(span) => parse_span(span),
+        Value::Array(terms) => parse_span(
+            &terms
+                .iter()
+                .map(|term| term.as_u64().map(|term| term.to_string()))
+                .collect::<Option<Vec<_>>>()?
+                .join(":"),
+        ),
+        _ => None,
+    }
+}
+
+fn parse_inspection_value(range: Option<Range>, value: Value) -> Result<Inspection> {
+    match value {
+        Value::String(value) => Ok(Inspection { range, value }),
+        Value::Object(object) => {
+            let value = object
+                .get("type")
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::from(format!("inspection has no type: {object:?}")))?;
+            Ok(Inspection {
+                range: object.get("span").and_then(parse_span_value).or(range),
+                value: value.to_string(),
+            })
+        }
+        value => Err(format!("unexpected inspection: {value}").into()),
+    }
+}
+
+/// Parses the output of `dmypy inspect`. Each non-empty line holds a JSON value, optionally
+/// prefixed with its span (`1:5:1:10 -> "int"`). A JSON array of inspections is also accepted.
+pub(crate) fn parse_inspect_output(output: &str) -> Result<Vec<Inspection>> {
+    let mut inspections = Vec::new();
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let (range, value) = match line.split_once(" -> ") {
+            Some((span, value)) if parse_span(span).is_some() => (parse_span(span), value),
+            _ => (None, line),
+        };
+        match serde_json::from_str(value)? {
+            Value::Array(values) => {
+                for value in values {
+                    inspections.push(parse_inspection_value(range, value)?);
+                }
+            }
+            value => inspections.push(parse_inspection_value(range, value)?),
+        }
+    }
+    Ok(inspections)
+}
+
+/// Picks the inspection whose span covers `position`, falling back to the first one.
+pub(crate) fn select_inspection(
+    inspections: Vec<Inspection>,
+    position: Position,
+) -> Option<Inspection> {
+    let index = inspections
+        .iter()
+        .position(|inspection| inspection.covers(position))
+        .unwrap_or(0);
+    inspections.into_iter().nth(index)
+}
+
+/// Formats a 1-based `file:line:column` location for `dmypy inspect` from an LSP position.
+pub(crate) fn format_location(path: &Path, position: Position) -> String {
+    format!(
+        "{}:{}:{}",
+        path.display(),
+        position.line + 1,
+        position.character + 1
+    )
+}
+
+/// Parses the output of `dmypy inspect --show definition`, which reports one or more
+/// `file:line:column:name` locations, comma- or newline-separated.
+pub(crate) fn parse_definitions(output: &str) -> Vec<(PathBuf, Position)> {
+    let re = Regex::new(r"^(.+?):(\d+):(\d+)(?::[^:]*)?$").unwrap();
+    output
+        .lines()
+        .map(|line| line.split_once(" -> ").map_or(line, |(_, value)| value))
+        .flat_map(|line| line.split(", "))
+        .filter_map(|definition| {
+            let caps = re.captures(definition.trim())?;
+            let line: u32 = caps.get(2)?.as_str().parse().ok()?;
+            let column: u32 = caps.get(3)?.as_str().parse().ok()?;
+            Some((
+                PathBuf::from(caps.get(1)?.as_str()),
+                Position {
+                    line: line.saturating_sub(1),
+                    character: column.saturating_sub(1),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Parses the output of `dmypy inspect --show attrs`: a JSON object per line mapping each class
+/// in the MRO of the inspected expression to its attribute names. Returns (class, attribute)
+/// pairs, without duplicates.
+pub(crate) fn parse_attrs(output: &str) -> Result<Vec<(String, String)>> {
+    let mut attrs: Vec<(String, String)> = Vec::new();
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let value = line.split_once(" -> ").map_or(line, |(_, value)| value);
+        let Value::Object(classes) = serde_json::from_str(value)? else {
+            return Err(format!("unexpected attrs inspection: {line}").into());
+        };
+        for (class, names) in classes {
+            for name in names
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+            {
+                if !attrs.iter().any(|(_, existing)| existing == name) {
+                    attrs.push((class.clone(), name.to_string()));
+                }
+            }
+        }
+    }
+    Ok(attrs)
+}
+
+/// Finds the position of the expression whose attributes are being completed, i.e. the last
+/// character before the `.` preceding the (possibly partial) identifier at `position`.
+pub(crate) fn completion_target(text: &str, position: Position) -> Option<Position> {
+    let line = text.lines().nth(position.line as usize)?;
+    let before: Vec<char> = line.chars().take(position.character as usize).collect();
+    let mut index = before.len();
+    while index > 0 && (before[index - 1].is_alphanumeric() || before[index - 1] == '_') {
+        index -= 1;
+    }
+    if index < 2 || before[index - 1] != '.' {
+        return None;
+    }
+    Some(Position {
+        line: position.line,
+        character: (index - 2) as u32,
+    })
+}
+
+/// Whether `c` can be part of a Python identifier.
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Finds the identifier at (or just before) `position`, returning it and its range.
+pub(crate) fn identifier_at(text: &str, position: Position) -> Option<(String, Range)> {
+    let line: Vec<char> = text.lines().nth(position.line as usize)?.chars().collect();
+    let at = (position.character as usize).min(line.len());
+    let mut start = at;
+    while start > 0 && is_identifier_char(line[start - 1]) {
+        start -= 1;
+    }
+    let mut end = at;
+    while end < line.len() && is_identifier_char(line[end]) {
+        end += 1;
+    }
+    if start == end || line[start].is_numeric() {
+        return None;
+    }
+    let range = Range {
+        start: Position {
+            line: position.line,
+            character: start as u32,
+        },
+        end: Position {
+            line: position.line,
+            character: end as u32,
+        },
+    };
+    Some((line[start..end].iter().collect(), range))
+}
+
+/// Finds every occurrence of the identifier `name` in `text` that isn't part of a longer
+/// identifier.
+pub(crate) fn identifier_occurrences(text: &str, name: &str) -> Vec<Range> {
+    let name: Vec<char> = name.chars().collect();
+    let mut occurrences = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line: Vec<char> = line.chars().collect();
+        for start in 0..line.len().saturating_sub(name.len() - 1) {
+            let end = start + name.len();
+            if line[start..end] == name[..]
+                && (start == 0 || !is_identifier_char(line[start - 1]))
+                && (end == line.len() || !is_identifier_char(line[end]))
+            {
+                occurrences.push(Range {
+                    start: Position {
+                        line: line_number as u32,
+                        character: start as u32,
+                    },
+                    end: Position {
+                        line: line_number as u32,
+                        character: end as u32,
+                    },
+                });
+            }
+        }
+    }
+    occurrences
+}
+
+/// Reads the docstring of the function or class defined on the 0-based `line` of `text`, with its
+/// indentation removed. Returns `None` if there is no definition there or it has no docstring.
+pub(crate) fn docstring(text: &str, line: u32) -> Option<String> {
+    let mut lines = text.lines().skip(line as usize);
+    let header = lines.next()?.trim_start();
+    let header = header.strip_prefix("async ").unwrap_or(header);
+    if !header.starts_with("def ") && !header.starts_with("class ") {
+        return None;
+    }
+    // The signature may span several lines; the body starts after the one ending with a colon.
+    if !header.trim_end().ends_with(':') {
+        lines.find(|line| line.trim_end().ends_with(':'))?;
+    }
+    let first = lines.by_ref().find(|line| !line.trim().is_empty())?.trim();
+    let first = first
+        .strip_prefix(['r', 'R', 'u', 'U'])
+        .filter(|rest| rest.starts_with(['"', '\'']))
+        .unwrap_or(first);
+    let quote = ["\"\"\"", "'''", "\"", "'"]
+        .into_iter()
+        .find(|quote| first.starts_with(quote))?;
+    let first = &first[quote.len()..];
+    let mut body = Vec::new();
+    match first.find(quote) {
+        Some(end) => body.push(&first[..end]),
+        None => {
+            body.push(first);
+            for line in lines {
+                match line.find(quote) {
+                    Some(end) => {
+                        body.push(&line[..end]);
+                        break;
+                    }
+                    None => body.push(line),
+                }
+            }
+        }
+    }
+    let indent = body
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let docstring = body
+        .iter()
+        .enumerate()
+        .map(|(index, line)| match index {
+            0 => line.trim(),
+            _ => line.get(indent..).unwrap_or(line.trim_start()).trim_end(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let docstring = docstring.trim();
+    (!docstring.is_empty()).then(|| docstring.to_string())
+}
+
+/// Finds the names assigned without annotations (`x = ...`) on lines within `range`, returning
+/// the range of each name.
+pub(crate) fn unannotated_assignments(text: &str, range: Range) -> Vec<Range> {
+    let re = Regex::new(r"^(\s*)([A-Za-z_]\w*)\s*=(?:[^=]|$)").unwrap();
+    text.lines()
+        .enumerate()
+        .skip(range.start.line as usize)
+        .take_while(|(line_number, _)| *line_number <= range.end.line as usize)
+        .filter_map(|(line_number, line)| {
+            let caps = re.captures(line)?;
+            let indent = caps.get(1)?.as_str().chars().count() as u32;
+            let name = caps.get(2)?.as_str().chars().count() as u32;
+            let line = line_number as u32;
+            Some(Range {
+                start: Position {
+                    line,
+                    character: indent,
+                },
+                end: Position {
+                    line,
+                    character: indent + name,
+                },
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_inspect_output_plain() {
+    let inspections = parse_inspect_output("\"builtins.int\"\n").unwrap();
+    assert_eq!(
+        inspections,
+        vec![Inspection {
+            range: None,
+            value: "builtins.int".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn test_select_inspection_by_span() {
+    let output = "1:1:1:20 -> \"builtins.list[builtins.int]\"\n\
+                  1:10:1:12 -> \"builtins.int\"\n";
+    let inspections = parse_inspect_output(output).unwrap();
+    assert_eq!(inspections.len(), 2);
+    let position = Position {
+        line: 0,
+        character: 10,
+    };
+    let inspection = select_inspection(inspections.clone(), position).unwrap();
+    assert_eq!(inspection.value, "builtins.list[builtins.int]");
+    let inspections: Vec<Inspection> = inspections.into_iter().rev().collect();
+    let inspection = select_inspection(inspections, position).unwrap();
+    assert_eq!(inspection.value, "builtins.int");
+}
+
+#[test]
+fn test_parse_inspect_output_json_spans() {
+    let output =
+        r#"[{"span": [2, 1, 2, 4], "type": "str"}, {"span": [3, 1, 3, 4], "type": "int"}]"#;
+    let inspections = parse_inspect_output(output).unwrap();
+    let position = Position {
+        line: 2,
+        character: 2,
+    };
+    assert_eq!(
+        select_inspection(inspections, position).unwrap().value,
+        "int"
+    );
+}
+
+#[test]
+fn test_parse_inspect_output_invalid() {
+    assert!(parse_inspect_output("Daemon is not running").is_err());
+}
+
+#[test]
+fn test_format_location() {
+    let position = Position {
+        line: 4,
+        character: 0,
+    };
+    assert_eq!(
+        format_location(Path::new("/a/b.py"), position),
+        "/a/b.py:5:1"
+    );
+}
+
+#[test]
+fn test_parse_definitions() {
+    let definitions = parse_definitions("/project/pkg/mod.py:10:5:helper\n");
+    assert_eq!(
+        definitions,
+        vec![(
+            PathBuf::from("/project/pkg/mod.py"),
+            Position {
+                line: 9,
+                character: 4
+            }
+        )]
+    );
+    let definitions = parse_definitions("a.py:1:1:f, b.py:2:3:g");
+    assert_eq!(definitions.len(), 2);
+    assert_eq!(definitions[1].0, PathBuf::from("b.py"));
+    assert!(parse_definitions("No known definition").is_empty());
+}
+
+#[test]
+fn test_parse_attrs() {
+    let output = r#"{"Foo": ["bar", "baz"], "object": ["__eq__", "bar"]}"#;
+    let attrs = parse_attrs(output).unwrap();
+    assert_eq!(
+        attrs,
+        vec![
+            ("Foo".to_string(), "bar".to_string()),
+            ("Foo".to_string(), "baz".to_string()),
+            ("object".to_string(), "__eq__".to_string()),
+        ]
+    );
+    assert!(parse_attrs("\"builtins.int\"").is_err());
+}
+
+#[test]
+fn test_completion_target() {
+    let text = "import os\nfoo.ba\nx = 1\n";
+    let target = Position {
+        line: 1,
+        character: 2,
+    };
+    let at = |character| Position { line: 1, character };
+    assert_eq!(completion_target(text, at(4)), Some(target));
+    assert_eq!(completion_target(text, at(6)), Some(target));
+    assert_eq!(completion_target(text, at(3)), None);
+    assert_eq!(
+        completion_target(
+            text,
+            Position {
+                line: 2,
+                character: 5
+            }
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_identifier_at() {
+    let text = "total = count_1 + 2\n";
+    let at = |character| identifier_at(text, Position { line: 0, character });
+    let range = |start, end| Range {
+        start: Position {
+            line: 0,
+            character: start,
+        },
+        end: Position {
+            line: 0,
+            character: end,
+        },
+    };
+    assert_eq!(at(2), Some(("total".to_string(), range(0, 5))));
+    assert_eq!(at(5), Some(("total".to_string(), range(0, 5))));
+    assert_eq!(at(10), Some(("count_1".to_string(), range(8, 15))));
+    assert_eq!(at(17), None);
+    assert_eq!(at(18), None);
+    assert_eq!(identifier_at(text, Position::new(3, 0)), None);
+}
+
+#[test]
+fn test_identifier_occurrences() {
+    let text = "x = 1\nxs = [x, x_1]\nprint(x)\n";
+    let starts: Vec<(u32, u32)> = identifier_occurrences(text, "x")
+        .into_iter()
+        .map(|range| (range.start.line, range.start.character))
+        .collect();
+    assert_eq!(starts, [(0, 0), (1, 6), (2, 6)]);
+    assert!(identifier_occurrences(text, "missing").is_empty());
+}
+
+#[test]
+fn test_docstring() {
+    let text = r#"import os
+
+def helper(x: int) -> int:
+    """Doubles x."""
+    return x * 2
+
+class Widget(
+    Base,
+):
+    r'''A widget.
+
+    It does things.
+    '''
+
+async def fetch():
+    pass
+
+value = 1
+"#;
+    assert_eq!(docstring(text, 2).as_deref(), Some("Doubles x."));
+    assert_eq!(
+        docstring(text, 6).as_deref(),
+        Some("A widget.\n\nIt does things.")
+    );
+    assert_eq!(docstring(text, 14), None);
+    assert_eq!(docstring(text, 17), None);
+    assert_eq!(docstring(text, 100), None);
+}
+
+#[test]
+fn test_unannotated_assignments() {
+    let text = "x = 1\ny: int = 2\nif x == 1:\n    total = x\nz=3\n";
+    let range = Range {
+        start: Position {
+            line: 0,
+            character: 0,
+        },
+        end: Position {
+            line: 3,
+            character: 0,
+        },
+    };
+    let name = |line, start, end| Range {
+        start: Position {
+            line,
+            character: start,
+        },
+        end: Position {
+            line,
+            character: end,
+        },
+    };
+    assert_eq!(
+        unannotated_assignments(text, range),
+        vec![name(0, 0, 1), name(3, 4, 9)]
+    );
+}
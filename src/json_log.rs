@@ -0,0 +1,127 @@
+use regex::Regex;
+use serde_json::json;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes one JSON object per log record, for consumption by tools rather than people.
+struct JsonLogger {
+    file: Mutex<File>,
+    level: log::LevelFilter,
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(SystemTime::now(), record);
+        let mut file = self.file.lock().unwrap();
+        let _ = writeln!(file, "{line}");
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+/// Installs a logger writing JSON lines to `path`, appending to it if it exists.
+pub(crate) fn log_to_file(path: &std::path::Path, level: log::LevelFilter) -> std::io::Result<()> {
+    let file = File::options().create(true).append(true).open(path)?;
+    let logger = JsonLogger {
+        file: Mutex::new(file),
+        level,
+    };
+    log::set_logger(Box::leak(Box::new(logger)))
+        .map_err(|error| std::io::Error::other(error.to_string()))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn format_record(time: SystemTime, record: &log::Record) -> String {
+    let message = record.args().to_string();
+    // Errors logged via `Context::ok_or_log` end with the location they were created at.
+    let re = Regex::new(r"\[location=([^\]]+)\]\s*$").unwrap();
+    let location = re.captures(&message).map(|caps| caps[1].to_string());
+    json!({
+        "timestamp": format_timestamp(time),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message,
+        "location": location,
+    })
+    .to_string()
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC with millisecond precision.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+    // Converts days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`.
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[test]
+fn test_format_timestamp() {
+    let time = |millis| UNIX_EPOCH + std::time::Duration::from_millis(millis);
+    assert_eq!(format_timestamp(time(0)), "1970-01-01T00:00:00.000Z");
+    assert_eq!(
+        format_timestamp(time(951_825_845_123)),
+        "2000-02-29T12:04:05.123Z"
+    );
+    assert_eq!(
+        format_timestamp(time(1_735_689_599_999)),
+        "2024-12-31T23:59:59.999Z"
+    );
+}
+
+#[test]
+fn test_format_record() {
+    let line = format_record(
+        UNIX_EPOCH,
+        &log::Record::builder()
+            .args(format_args!(
+                "Failed to check file: error: boom [location=src/main.rs:10:5]"
+            ))
+            .level(log::Level::Error)
+            .target("dmypyls")
+            .build(),
+    );
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(
+        value,
+        json!({
+            "timestamp": "1970-01-01T00:00:00.000Z",
+            "level": "ERROR",
+            "target": "dmypyls",
+            "message": "Failed to check file: error: boom [location=src/main.rs:10:5]",
+            "location": "src/main.rs:10:5",
+        })
+    );
+}
@@ -1,19 +1,36 @@
-use crate::config::DmypylsConfig;
-use crate::error::{Context, Result};
+use crate::config::{DmypylsConfig, LogFormat};
+use crate::error::{Context, ErrorKind, Result};
 use crate::relpathbuf::RelPathBuf;
+use crate::runner::CommandRunner;
 use regex::{Captures, Regex};
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
 use tower_lsp::jsonrpc::Result as TowerResult;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{LspService, Server};
 
 mod config;
+mod directive;
 mod error;
+mod error_codes;
+mod inspect;
+mod json_log;
+mod mypy_config;
 mod relpathbuf;
+mod runner;
+mod suggest;
+mod text;
+mod type_ignore;
+mod version;
+
+const CONFIG_FILE_NAME: &str = concat!(env!("CARGO_PKG_NAME"), ".yaml");
+const PYPROJECT_FILE_NAME: &str = "pyproject.toml";
 
 const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
 
@@ -30,59 +47,196 @@ macro_rules! maybe {
     };
 }
 
-fn setup_logging(base_dirs: &xdg::BaseDirectories, level: log::LevelFilter) -> Result<()> {
-    let log_file_path = base_dirs.place_state_file("dmypyls.log")?;
-    simple_logging::log_to_file(log_file_path, level)?;
+fn setup_logging(
+    base_dirs: &xdg::BaseDirectories,
+    log_file: Option<PathBuf>,
+    level: log::LevelFilter,
+    format: LogFormat,
+) -> Result<()> {
+    let log_file_path = match log_file {
+        Some(log_file) => log_file,
+        None => base_dirs.place_state_file("dmypyls.log")?,
+    };
+    match format {
+        LogFormat::Text => simple_logging::log_to_file(log_file_path, level)?,
+        LogFormat::Json => crate::json_log::log_to_file(&log_file_path, level)?,
+    }
     Ok(())
 }
 
 fn read_config_from_file(filename: &Path) -> Result<Option<DmypylsConfig>> {
     log::info!("attempting to read configuration from {filename:?}");
-    let config = (|| {
-        crate::config::parse_config(read_to_string(filename).ok()?.as_str())
-            .context("failed to parse YAML configuration")
-            .ok_or_log("failed to parse configuration")
-    })();
+    let Ok(content) = read_to_string(filename) else {
+        log::info!(
+            "configuration from {} could not be read.",
+            filename.display()
+        );
+        return Ok(None);
+    };
+    let config = crate::config::parse_config(&content).context(&format!(
+        "failed to parse YAML configuration in {}",
+        filename.display()
+    ))?;
     log::info!(
-        "configuration from {} {}read.",
-        filename.display(),
-        if config.is_some() {
-            "successfully "
-        } else {
-            "could not be "
-        }
+        "configuration from {} successfully read.",
+        filename.display()
     );
+    Ok(Some(config))
+}
+
+/// Reads the `[tool.dmypyls]` table from a `pyproject.toml`, if the file exists and has one.
+fn read_config_from_pyproject(filename: &Path) -> Result<Option<DmypylsConfig>> {
+    log::info!("attempting to read configuration from {filename:?}");
+    let Ok(content) = read_to_string(filename) else {
+        log::info!(
+            "configuration from {} could not be read.",
+            filename.display()
+        );
+        return Ok(None);
+    };
+    let config = crate::config::parse_config_toml(&content).context(&format!(
+        "failed to parse [tool.dmypyls] configuration in {}",
+        filename.display()
+    ))?;
+    if config.is_some() {
+        log::info!(
+            "configuration from {} successfully read.",
+            filename.display()
+        );
+    }
     Ok(config)
 }
 
+/// Walks up from `start` looking for a project-level file named `file_name`, stopping at the root
+/// of the repository (a directory containing `.git`).
+fn find_project_config(start: &Path, file_name: &str) -> Option<PathBuf> {
+    for dir in start.ancestors() {
+        let config_file = dir.join(file_name);
+        if config_file.is_file() {
+            return Some(config_file);
+        }
+        if dir.join(".git").exists() {
+            return None;
+        }
+    }
+    None
+}
+
 /// Read configuration from the user-level configuration file and the project-level configuration
-/// file. Prefers project-level. Does not merge configs.
-fn read_config(base_dirs: &xdg::BaseDirectories) -> Result<DmypylsConfig> {
-    let config_leaf_name = format!("{}.yaml", env!("CARGO_PKG_NAME"));
-    let current_dir = std::env::current_dir()?;
-    let project_config = read_config_from_file(&current_dir.join(&config_leaf_name))?;
-    if project_config.is_some() {
-        log::info!("[read_config] project-level configuration read.");
-        return Ok(project_config.unwrap());
-    }
-    let user_level_config_filename = base_dirs.get_config_file(&config_leaf_name);
+/// file, which is searched for upwards from `start`. Prefers project-level, and within the project
+/// prefers `dmypyls.yaml` over the `[tool.dmypyls]` table of `pyproject.toml`. Does not merge
+/// configs. Returns `None` if no configuration exists.
+fn read_config(base_dirs: &xdg::BaseDirectories, start: &Path) -> Result<Option<DmypylsConfig>> {
+    if let Some(config_file) = find_project_config(start, CONFIG_FILE_NAME) {
+        if let Some(project_config) = read_config_from_file(&config_file)? {
+            log::info!("[read_config] project-level configuration read.");
+            return Ok(Some(project_config));
+        }
+    }
+    if let Some(pyproject_file) = find_project_config(start, PYPROJECT_FILE_NAME) {
+        if let Some(project_config) = read_config_from_pyproject(&pyproject_file)? {
+            log::info!("[read_config] project-level configuration read from pyproject.toml.");
+            return Ok(Some(project_config));
+        }
+    }
+    let user_level_config_filename = base_dirs.get_config_file(CONFIG_FILE_NAME);
     let user_config = read_config_from_file(&user_level_config_filename)?;
     if user_config.is_some() {
         log::info!("[read_config] user-level configuration read.");
     }
-    user_config.ok_or("No configuration found".into())
+    Ok(user_config)
+}
+
+/// Handles `--check-config`: prints the configuration that would be used and checks that the
+/// configured dmypy (or mypy, in one-shot mode) command can be run. Returns the exit code.
+async fn check_config(base_dirs: &xdg::BaseDirectories) -> i32 {
+    let root_dir = std::env::current_dir().unwrap_or_default();
+    let config = match read_config(base_dirs, &root_dir) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("No {CONFIG_FILE_NAME} found; using the default configuration.");
+            DmypylsConfig::default()
+        }
+        Err(error) => {
+            eprintln!("Invalid configuration: {error}");
+            return 1;
+        }
+    };
+    match serde_yml::to_string(&config) {
+        Ok(yaml) => println!("{yaml}"),
+        Err(_) => println!("{config:#?}"),
+    }
+    let cmd = if config.uses_daemon() {
+        println!(
+            "dmypy command: {}",
+            config.resolve_command(&root_dir).join(" ")
+        );
+        config
+            .daemon_command(&root_dir, &default_status_file(base_dirs, &root_dir))
+            .map(|mut cmd| {
+                cmd.arg("status");
+                cmd
+            })
+    } else {
+        config.mypy_command(&root_dir).map(|mut cmd| {
+            cmd.arg("--version");
+            cmd
+        })
+    };
+    let output = match cmd {
+        Ok(mut cmd) => cmd.output().await,
+        Err(error) => {
+            eprintln!("Invalid command: {error}");
+            return 1;
+        }
+    };
+    match output {
+        Ok(output) => {
+            print!("{}", String::from_utf8_lossy(&output.stdout));
+            eprint!("{}", String::from_utf8_lossy(&output.stderr));
+            0
+        }
+        Err(error) => {
+            eprintln!("Failed to run the configured command: {error}");
+            1
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let base_dirs = xdg::BaseDirectories::with_prefix(env!("CARGO_PKG_NAME")).unwrap();
+    if std::env::args().skip(1).any(|arg| arg == "--version") {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if std::env::args().skip(1).any(|arg| arg == "--check-config") {
+        std::process::exit(check_config(&base_dirs).await);
+    }
+    // Configuration is read before logging is set up, since it may configure the log.
+    let config = read_config(&base_dirs, &std::env::current_dir()?)
+        .context("Failed to read configuration")?;
+    let using_default_config = config.is_none();
+    let config = config.unwrap_or_default();
+    let default_log_level = match &config.log_level {
+        Some(level) => level.parse()?,
+        None => DEFAULT_LOG_LEVEL,
+    };
     let log_level: log::LevelFilter = std::env::var("RUST_LOG_LEVEL")
-        .map_or(DEFAULT_LOG_LEVEL, |level| {
-            level.parse().unwrap_or(DEFAULT_LOG_LEVEL)
+        .map_or(default_log_level, |level| {
+            level.parse().unwrap_or(default_log_level)
         });
-    setup_logging(&base_dirs, log_level).context("failed to set up logging")?;
+    setup_logging(&base_dirs, config.log_file(), log_level, config.log_format)
+        .context("failed to set up logging")?;
+    if using_default_config {
+        log::warn!(
+            "No dmypyls.yaml found in the project or {:?}; using the default configuration.",
+            base_dirs.get_config_home()
+        );
+    }
+    log::info!("dmypyls version {}", env!("CARGO_PKG_VERSION"));
+    log::info!("Configuration: {config:?}");
 
-    let config = read_config(&base_dirs).expect("Failed to read configuration");
     log::info!(
         "Current working directory: {:?}",
         std::env::current_dir().unwrap()
@@ -91,11 +245,13 @@ async fn main() -> Result<()> {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::build(|client| Backend {
-        client,
-        config,
-        root_dir: std::env::current_dir().unwrap(),
-        versions: Arc::new(Mutex::new(Default::default())),
+    let (service, socket) = LspService::build(|client| {
+        Backend::new(
+            client,
+            base_dirs,
+            config,
+            Box::new(crate::runner::ProcessRunner),
+        )
     })
     .finish();
 
@@ -105,33 +261,169 @@ async fn main() -> Result<()> {
 
 struct Backend {
     client: tower_lsp::Client,
-    config: DmypylsConfig,
-    root_dir: PathBuf,
+    runner: Arc<dyn CommandRunner>,
+    base_dirs: xdg::BaseDirectories,
+    /// The configuration, which may be replaced when the client changes its settings.
+    config: Arc<RwLock<Arc<DmypylsConfig>>>,
+    /// The project root. Defaults to the current directory until `initialize` provides one.
+    root_dir: Arc<RwLock<PathBuf>>,
     versions: Arc<Mutex<HashMap<Url, i32>>>,
+    /// Counts check requests per file so that a burst of requests results in a single check.
+    pending_checks: Arc<Mutex<HashMap<Url, u64>>>,
+    /// The latest text of each open document, as sent by the client.
+    documents: Arc<Mutex<HashMap<Url, String>>>,
+    /// Inlay hints computed for a version of a document, keyed by the (line, character) start of the
+    /// hinted name.
+    #[allow(clippy::type_complexity)]
+    inlay_hints: Arc<Mutex<HashMap<Url, (i32, HashMap<(u32, u32), Option<InlayHint>>)>>>,
+    /// Whether the running daemon has completed a check, so that files can be rechecked
+    /// incrementally with `dmypy recheck --update`.
+    daemon_warm: AtomicBool,
+    /// The token of the latest check of each file, cancelled when a newer check of it starts.
+    cancellations: Arc<Mutex<HashMap<Url, CancellationToken>>>,
+    /// Files waiting to be checked together, see `check_file_coalesced`.
+    queued_checks: Arc<Mutex<Vec<(Url, i32)>>>,
+    /// The diagnostics last published for each file, to avoid re-publishing identical results.
+    published: Arc<Mutex<HashMap<Url, HashSet<MypyLsDiagnostic>>>>,
+    /// Set by `initialized` once the daemon is running. Checks requested before then are
+    /// deferred until it is.
+    ready: AtomicBool,
+    /// The types revealed by `reveal_type(...)` in each file at its last check, shown on hover when
+    /// `reveal_type_as_hint` is enabled.
+    #[allow(clippy::type_complexity)]
+    revealed_types: Arc<Mutex<HashMap<Url, Vec<(Range, String)>>>>,
+    /// Whether dmypy has the `inspect` subcommand, which hover, definitions, completions and inlay
+    /// hints rely on. Detected by `initialize`.
+    supports_inspect: AtomicBool,
+    /// The version of mypy behind the configured command, if it could be detected.
+    mypy_version: RwLock<Option<crate::version::MypyVersion>>,
+    /// Whether the client accepts server-initiated progress, for reporting checks as they run.
+    supports_progress: AtomicBool,
+    /// Whether checking has been paused with the `dmypyls.togglePause` command.
+    paused: AtomicBool,
+    /// When a file was last checked or hovered, for stopping the daemon after
+    /// `idle_timeout_secs`.
+    last_activity: Arc<Mutex<std::time::Instant>>,
+    /// Set when the daemon was stopped for being idle, so that the next check restarts it.
+    stopped_idle: Arc<AtomicBool>,
+    /// When dmypyls last started the daemon, for reporting its uptime.
+    daemon_started: Mutex<Option<std::time::Instant>>,
+    /// The number of checks running, which `shutdown` waits for before stopping the daemon.
+    checks_in_flight: std::sync::atomic::AtomicUsize,
+    /// Notified whenever a check finishes.
+    check_finished: tokio::sync::Notify,
+    /// Limits the checks running at once to `max_concurrent_checks`.
+    check_permits: tokio::sync::Semaphore,
+}
+
+/// Counts a check as in flight for as long as it is held.
+struct InFlightCheck<'a>(&'a Backend);
+
+impl<'a> InFlightCheck<'a> {
+    fn new(backend: &'a Backend) -> Self {
+        backend.checks_in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(backend)
+    }
+}
+
+impl Drop for InFlightCheck<'_> {
+    fn drop(&mut self) {
+        self.0.checks_in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.check_finished.notify_waiters();
+    }
 }
 
-const MYPY_ERROR_REGEX: &str = r"(.*):(\d+):(\d+):(\d+):(\d+): (\w+): (.*)";
+/// The most occurrences `document_highlight` resolves with `dmypy inspect`, all at once. Beyond
+/// this, every textual match is highlighted.
+const MAX_HIGHLIGHT_INSPECTIONS: usize = 8;
+
+/// How long to wait for more files to be opened before checking them together.
+const COALESCE_WINDOW: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// The delay before the first retry of a transiently failed check, doubled for each further retry.
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How long `shutdown` waits for running checks to finish before stopping the daemon anyway.
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How often, and how many times, `initialized` checks whether the daemon is running.
+const DAEMON_READY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+const DAEMON_READY_ATTEMPTS: usize = 10;
+
+const RESTART_DAEMON_COMMAND: &str = "dmypyls.restartDaemon";
+const RECHECK_WORKSPACE_COMMAND: &str = "dmypyls.recheckWorkspace";
+const TOGGLE_PAUSE_COMMAND: &str = "dmypyls.togglePause";
+const SELF_TEST_COMMAND: &str = "dmypyls.selfTest";
+const DUMP_DIAGNOSTICS_COMMAND: &str = "dmypyls.dumpDiagnostics";
+const DAEMON_STATS_COMMAND: &str = "dmypyls.daemonStats";
+
+/// Flags that make mypy's output parseable by `MYPY_ERROR_REGEX`.
+const MYPY_OUTPUT_FLAGS: &[&str] = &[
+    "--show-absolute-path",
+    "--show-column-numbers",
+    "--show-error-end",
+    "--hide-error-context",
+    "--no-color-output",
+    "--no-error-summary",
+    "--no-pretty",
+];
+
+/// The file is matched lazily, optionally starting with a Windows drive letter, so that the colon
+/// in e.g. `C:\project\foo.py` isn't mistaken for a separator.
+const MYPY_ERROR_REGEX: &str = r"^(?P<file>(?:[A-Za-z]:)?.*?):(?P<line>\d+):(?P<column>\d+):(?P<end_line>\d+):(?P<end_column>\d+): (?P<severity>\w+): (?P<message>.*?)(?:\s+\[(?P<code>[\w-]+)\])?$";
+
+/// Used when mypy doesn't report end positions (no `--show-error-end`).
+const MYPY_ERROR_REGEX_NO_END: &str = r"^(?P<file>(?:[A-Za-z]:)?.*?):(?P<line>\d+):(?P<column>\d+): (?P<severity>\w+): (?P<message>.*?)(?:\s+\[(?P<code>[\w-]+)\])?$";
+
+/// Maps the severity mypy reports (`error`, `warning` or `note`) to an LSP severity. Unknown
+/// severities are reported as information rather than left unset, which editors tend to show as
+/// errors.
+fn mypy_severity(severity: &str) -> DiagnosticSeverity {
+    match severity {
+        "error" => DiagnosticSeverity::ERROR,
+        "warning" => DiagnosticSeverity::WARNING,
+        "note" => DiagnosticSeverity::INFORMATION,
+        _ => {
+            log::warn!("unknown mypy severity {severity:?}");
+            DiagnosticSeverity::INFORMATION
+        }
+    }
+}
 
 fn convert_capture_to_diagnostic(
+    config: &DmypylsConfig,
     root_dir: &Path,
-    target_filename: &RelPathBuf,
     caps: Captures,
-) -> Option<Diagnostic> {
-    let filename = RelPathBuf::from_filename(root_dir, caps.get(1)?.as_str()).ok()?;
-    if *target_filename != filename {
-        log::info!(
-            "ignoring diagnostic for {filename:?} [target_abs_filename={target_filename:?}]"
-        );
-        return None;
-    }
-    let start_line: u32 = caps.get(2)?.as_str().parse().ok()?;
-    let start_column: u32 = caps.get(3)?.as_str().parse().ok()?;
-    let end_line: u32 = caps.get(4)?.as_str().parse().ok()?;
-    let end_column: u32 = caps.get(5)?.as_str().parse().ok()?;
-    let severity: &str = caps.get(6)?.as_str();
-    let description: &str = caps.get(7)?.as_str();
+) -> Option<(RelPathBuf, Diagnostic)> {
+    let filename = RelPathBuf::from_filename(root_dir, caps.name("file")?.as_str()).ok()?;
+    let start_line: u32 = caps.name("line")?.as_str().parse().ok()?;
+    let start_column: u32 = caps.name("column")?.as_str().parse().ok()?;
+    // Without end positions, the diagnostic covers just its start position.
+    let end_line: u32 = match caps.name("end_line") {
+        Some(end_line) => end_line.as_str().parse().ok()?,
+        None => start_line,
+    };
+    let end_column: u32 = match caps.name("end_column") {
+        Some(end_column) => end_column.as_str().parse().ok()?,
+        None => start_column,
+    };
+    // Some editors don't render zero-width ranges, so cover at least one character. When the
+    // file's text is known, `clamp_range` keeps this within the line.
+    let (end_line, end_column) = match (end_line, end_column) <= (start_line, start_column) {
+        true => (start_line, start_column.max(1) + 1),
+        false => (end_line, end_column),
+    };
+    let severity: &str = caps.name("severity")?.as_str();
+    let description: &str = caps.name("message")?.as_str();
+    let code: Option<&str> = caps.name("code").map(|code| code.as_str());
+    let severity = config
+        .severity_override(code, severity)
+        .unwrap_or_else(|| match severity {
+            "note" => config.note_severity(),
+            severity => mypy_severity(severity),
+        });
 
-    Some(Diagnostic {
+    let diagnostic = Diagnostic {
         range: Range {
             start: Position {
                 line: start_line.saturating_sub(1),
@@ -143,251 +435,4033 @@ fn convert_capture_to_diagnostic(
             },
         },
         message: description.to_string(),
-        source: Some("dmypy".to_string()),
-        code: None,
-        code_description: None,
-        severity: DiagnosticSeverity::try_from(severity).ok(),
+        source: Some(config.diagnostic_source.clone()),
+        code: code.map(|code| NumberOrString::String(code.to_string())),
+        code_description: code
+            .and_then(crate::error_codes::documentation_url)
+            .map(|href| CodeDescription { href }),
+        severity: Some(severity),
         related_information: None,
-        tags: None,
+        tags: code.and_then(crate::error_codes::diagnostic_tags),
         data: None,
-    })
+    };
+    Some((filename, diagnostic))
+}
+
+/// The type in a `reveal_type` note, e.g. `builtins.int` in `Revealed type is "builtins.int"`.
+fn revealed_type(message: &str) -> Option<&str> {
+    message
+        .strip_prefix("Revealed type is \"")?
+        .strip_suffix('"')
+}
+
+/// Finds the types revealed by `reveal_type(...)` in dmypy output, with the span of each call.
+fn parse_revealed_types(
+    config: &DmypylsConfig,
+    root_dir: &Path,
+    output: &[u8],
+) -> Vec<(RelPathBuf, Range, String)> {
+    let re = Regex::new(MYPY_ERROR_REGEX).unwrap();
+    let re_no_end = Regex::new(MYPY_ERROR_REGEX_NO_END).unwrap();
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter_map(|line| re.captures(line).or_else(|| re_no_end.captures(line)))
+        .filter(|caps| caps.name("severity").is_some_and(|s| s.as_str() == "note"))
+        .filter_map(|caps| convert_capture_to_diagnostic(config, root_dir, caps))
+        .filter_map(|(filename, diagnostic)| {
+            let value = revealed_type(&diagnostic.message)?.to_string();
+            Some((filename, diagnostic.range, value))
+        })
+        .collect()
 }
 
-#[derive(Eq, PartialEq)]
+/// A diagnostic compared by its span, message (ignoring surrounding whitespace), source,
+/// severity and code.
 struct MypyLsDiagnostic(Diagnostic);
 
+impl PartialEq for MypyLsDiagnostic {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.range == other.0.range
+            && self.0.message.trim() == other.0.message.trim()
+            && self.0.source == other.0.source
+            && self.0.severity == other.0.severity
+            && self.0.code == other.0.code
+    }
+}
+
+impl Eq for MypyLsDiagnostic {}
+
 impl std::hash::Hash for MypyLsDiagnostic {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.0.range.start.line.hash(state);
         self.0.range.start.character.hash(state);
         self.0.range.end.line.hash(state);
         self.0.range.end.character.hash(state);
-        self.0.message.hash(state);
+        self.0.message.trim().hash(state);
         self.0.source.hash(state);
+        // `DiagnosticSeverity` doesn't implement `Hash`, but its `Debug` output is distinct.
+        self.0
+            .severity
+            .map(|severity| format!("{severity:?}"))
+            .hash(state);
+        self.0.code.hash(state);
     }
 }
 
-fn parse_diagnostics(
+impl MypyLsDiagnostic {
+    fn set_of(diagnostics: &[Diagnostic]) -> HashSet<MypyLsDiagnostic> {
+        diagnostics.iter().cloned().map(MypyLsDiagnostic).collect()
+    }
+}
+
+/// Whether a line of dmypy output is expected not to be a diagnostic: blank, or a summary such as
+/// `Success: no issues found in 1 source file`.
+fn is_output_noise(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty()
+        || line.starts_with("Success: ")
+        || (line.starts_with("Found ") && line.contains(" error"))
+}
+
+/// Parses dmypy output into diagnostics grouped by the file they were reported for. Notes that
+/// follow an error are attached to it as related information.
+fn parse_diagnostics_by_file(
     context: &str,
+    config: &DmypylsConfig,
     root_dir: &Path,
-    target_filename: &RelPathBuf,
     output: &[u8],
-) -> Result<Vec<Diagnostic>> {
+) -> Result<Vec<(RelPathBuf, Vec<Diagnostic>)>> {
     let re = Regex::new(MYPY_ERROR_REGEX).unwrap();
+    let re_no_end = Regex::new(MYPY_ERROR_REGEX_NO_END).unwrap();
     let output = std::str::from_utf8(output).context("from_utf8 failed for dmypy output")?;
+    // In case extra_run_args turned color back on.
+    let output = crate::text::strip_ansi_escapes(output);
     log::info!("[{context}/parse_diagnostics] parsing: {output}");
-    let diagnostics: HashSet<MypyLsDiagnostic> = output
-        .lines()
-        .filter_map(|line| {
-            convert_capture_to_diagnostic(root_dir, target_filename, re.captures(line)?)
+    let mut files: Vec<(RelPathBuf, Vec<Diagnostic>)> = Vec::new();
+    // The (file, diagnostic) indices of the most recent error.
+    let mut preceding_error: Option<(usize, usize)> = None;
+    // Whether the most recent error was ignored.
+    let mut ignoring_notes = false;
+    for line in output.lines() {
+        let caps = match re.captures(line) {
+            Some(caps) => {
+                log::debug!("[{context}/parse_diagnostics] matched full pattern: {line}");
+                caps
+            }
+            None => match re_no_end.captures(line) {
+                Some(caps) => {
+                    log::debug!("[{context}/parse_diagnostics] matched fallback pattern: {line}");
+                    caps
+                }
+                None => {
+                    if !is_output_noise(line) {
+                        log::debug!(
+                            "[{context}/parse_diagnostics] unmatched diagnostic line: {line}"
+                        );
+                    }
+                    continue;
+                }
+            },
+        };
+        let is_note = caps
+            .name("severity")
+            .is_some_and(|severity| severity.as_str() == "note");
+        let Some((filename, diagnostic)) = convert_capture_to_diagnostic(config, root_dir, caps)
+        else {
+            if !is_note {
+                preceding_error = None;
+            }
+            continue;
+        };
+        // Files are listed even if all their diagnostics are filtered out below, so that a run
+        // that reported anything isn't mistaken for a failure.
+        let file_index = match files.iter().position(|(file, _)| *file == filename) {
+            Some(file_index) => file_index,
+            None => {
+                files.push((filename.clone(), Vec::new()));
+                files.len() - 1
+            }
+        };
+        if is_note && config.reveal_type_as_hint && revealed_type(&diagnostic.message).is_some() {
+            // Shown on hover instead, see `parse_revealed_types`.
+            continue;
+        }
+        if !is_note {
+            ignoring_notes = false;
+        }
+        // Ignored errors are dropped along with the notes that elaborate on them.
+        let is_ignored = matches!(
+            &diagnostic.code,
+            Some(NumberOrString::String(code)) if config.is_ignored_code(code)
+        );
+        if ignoring_notes || is_ignored {
+            ignoring_notes = true;
+            preceding_error = None;
+            continue;
+        }
+        if let (true, Some((file_index, index))) = (is_note, preceding_error) {
+            // A note following an error elaborates on it.
+            files[file_index].1[index]
+                .related_information
+                .get_or_insert_with(Vec::new)
+                .push(DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: filename.to_uri()?,
+                        range: diagnostic.range,
+                    },
+                    message: diagnostic.message,
+                });
+            continue;
+        }
+        let diagnostics = &mut files[file_index].1;
+        diagnostics.push(diagnostic);
+        if !is_note {
+            preceding_error = Some((file_index, diagnostics.len() - 1));
+        }
+    }
+    Ok(files
+        .into_iter()
+        .map(|(filename, diagnostics)| {
+            let diagnostics: HashSet<MypyLsDiagnostic> =
+                diagnostics.into_iter().map(MypyLsDiagnostic).collect();
+            let mut diagnostics: Vec<Diagnostic> = diagnostics.into_iter().map(|d| d.0).collect();
+            // Deduplication loses mypy's ordering, so sort for a stable order between runs.
+            diagnostics.sort_by(|a, b| {
+                (a.range.start.line, a.range.start.character, &a.message).cmp(&(
+                    b.range.start.line,
+                    b.range.start.character,
+                    &b.message,
+                ))
+            });
+            (filename, diagnostics)
         })
-        .map(MypyLsDiagnostic)
-        .collect();
-    Ok(diagnostics.into_iter().map(|d| d.0).collect())
+        .collect())
+}
+
+/// Splits the diagnostics in `output` between `targets`, returning them in the same order.
+fn parse_diagnostics(
+    context: &str,
+    config: &DmypylsConfig,
+    root_dir: &Path,
+    targets: &[RelPathBuf],
+    output: &[u8],
+) -> Result<Vec<Vec<Diagnostic>>> {
+    let files = parse_diagnostics_by_file(context, config, root_dir, output)?;
+    Ok(diagnostics_by_target(targets, files))
+}
+
+/// Sorts the diagnostics parsed for each file into those of each of `targets`, ignoring other
+/// files.
+fn diagnostics_by_target(
+    targets: &[RelPathBuf],
+    files: Vec<(RelPathBuf, Vec<Diagnostic>)>,
+) -> Vec<Vec<Diagnostic>> {
+    let mut diagnostics = vec![Vec::new(); targets.len()];
+    for (filename, file_diagnostics) in files {
+        match targets.iter().position(|target| *target == filename) {
+            Some(index) => diagnostics[index] = file_diagnostics,
+            None => log::info!("ignoring diagnostics for {filename:?} [targets={targets:?}]"),
+        }
+    }
+    diagnostics
 }
 
 impl Backend {
-    async fn check_file(&self, context: &str, uri: Url, version: i32) -> Result<()> {
-        let file_path = RelPathBuf::from_uri(self.root_dir.clone(), uri.clone())?;
-        if file_path
-            .extension()
-            .unwrap_or_else(|| std::ffi::OsStr::new(""))
-            != "py"
-        {
-            log::info!("[{context}] ignoring non-Python file: {file_path:?}");
-            return Ok(());
+    fn new(
+        client: tower_lsp::Client,
+        base_dirs: xdg::BaseDirectories,
+        config: DmypylsConfig,
+        runner: Box<dyn CommandRunner>,
+    ) -> Self {
+        let check_permits = tokio::sync::Semaphore::new(config.max_concurrent_checks);
+        Backend {
+            client,
+            runner: Arc::from(runner),
+            base_dirs,
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            root_dir: Arc::new(RwLock::new(std::env::current_dir().unwrap())),
+            versions: Arc::new(Mutex::new(Default::default())),
+            pending_checks: Arc::new(Mutex::new(Default::default())),
+            documents: Arc::new(Mutex::new(Default::default())),
+            inlay_hints: Arc::new(Mutex::new(Default::default())),
+            daemon_warm: AtomicBool::new(false),
+            cancellations: Arc::new(Mutex::new(Default::default())),
+            queued_checks: Arc::new(Mutex::new(Default::default())),
+            published: Arc::new(Mutex::new(Default::default())),
+            ready: AtomicBool::new(false),
+            revealed_types: Arc::new(Mutex::new(Default::default())),
+            supports_inspect: AtomicBool::new(false),
+            mypy_version: RwLock::new(None),
+            supports_progress: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            last_activity: Arc::new(Mutex::new(std::time::Instant::now())),
+            daemon_started: Mutex::new(None),
+            stopped_idle: Arc::new(AtomicBool::new(false)),
+            checks_in_flight: Default::default(),
+            check_finished: tokio::sync::Notify::new(),
+            check_permits,
         }
-        log::info!("[{context}] checking file {file_path}:{version}");
-        let mut cmd = self.config.command()?;
-        cmd.arg("check").arg(file_path.as_os_str());
-        log::info!(
-            "[{context}] running command: {:?} [PWD={:?}]",
-            cmd,
-            std::env::current_dir()?
-        );
-        let output = cmd.output().context("Failed to execute dmypy check")?;
+    }
 
-        log::info!(
-            "[{context}] dmypy check succeeded: {:?}",
-            output.status.success()
-        );
-        log::info!(
-            "[{context}] dmypy check output: {}",
-            std::str::from_utf8(&output.stdout).unwrap()
-        );
-        let diagnostics: Vec<Diagnostic> =
-            parse_diagnostics(context, &self.root_dir, &file_path, &output.stdout)?;
-        log::info!("[{context}] diagnostics: {:?}", diagnostics);
-        self.client
-            .publish_diagnostics(uri, diagnostics, Some(version))
-            .await;
-        Ok(())
+    fn config(&self) -> Arc<DmypylsConfig> {
+        self.config.read().unwrap().clone()
     }
-}
 
-fn dmypy_is_running(config: &DmypylsConfig) -> Result<bool> {
-    Ok(config
-        .command()?
-        .arg("status")
-        .output()
-        .map_or(false, |output| {
-            let text = std::str::from_utf8(&output.stdout).unwrap();
-            text.starts_with("Daemon is up and running")
-        }))
-}
+    fn root_dir(&self) -> PathBuf {
+        self.root_dir.read().unwrap().clone()
+    }
 
-#[tower_lsp::async_trait]
-impl tower_lsp::LanguageServer for Backend {
-    async fn initialize(&self, params: InitializeParams) -> TowerResult<InitializeResult> {
-        log::info!("[initialize] Initializing dmypyls");
-        log::trace!(
-            "[initialize] client text document capabilities: {}",
-            serde_json::to_string(&params.capabilities.text_document).unwrap()
-        );
-        let root = "."; // Set root from params root_path or root_uri if available
-        if !dmypy_is_running(&self.config)? {
-            log::info!("[initialize] dmypy is not yet running, starting it...");
-            let ret = self
-                .config
-                .command()?
-                .arg("run")
-                .arg("--")
-                // .arg("--cache-fine-grained")
-                .arg("--show-absolute-path")
-                .arg("--show-column-numbers")
-                .arg("--show-error-end")
-                .arg("--hide-error-codes")
-                .arg("--hide-error-context")
-                .arg("--no-color-output")
-                .arg("--no-error-summary")
-                .arg("--no-pretty")
-                .arg(root)
-                .status();
-            log::info!("[initialize] dympy run status: {:?}", ret);
-        } else {
-            log::info!("[initialize] dmypy is already running");
-        }
+    /// The status file of this project's daemon, unless one is configured. It is derived from the
+    /// project root, so that each project gets its own daemon.
+    fn default_status_file(&self) -> PathBuf {
+        default_status_file(&self.base_dirs, &self.root_dir())
+    }
 
-        Ok(InitializeResult {
-            capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
-                )),
-                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
-                    DiagnosticOptions {
-                        identifier: None,
-                        inter_file_dependencies: false,
-                        workspace_diagnostics: false,
-                        work_done_progress_options: WorkDoneProgressOptions {
-                            work_done_progress: Some(false),
-                        },
-                    },
-                )),
-                hover_provider: Some(HoverProviderCapability::Simple(true)),
-                ..ServerCapabilities::default()
-            },
-            server_info: Some(ServerInfo {
-                name: "dmypyls".to_string(),
-                version: None,
-            }),
-        })
+    /// A dmypy command for this project's daemon.
+    fn dmypy_command(&self) -> Result<Command> {
+        self.dmypy_command_with(&self.config())
     }
 
-    async fn initialized(&self, _: InitializedParams) {}
+    fn dmypy_command_with(&self, config: &DmypylsConfig) -> Result<Command> {
+        let mut cmd = config.daemon_command(&self.root_dir(), &self.default_status_file())?;
+        cmd.current_dir(self.working_dir_with(config));
+        Ok(cmd)
+    }
 
-    async fn did_change_configuration(&self, dccp: DidChangeConfigurationParams) {
-        log::info!("did_change_configuration called");
-        if dccp.settings.is_null() {
-            return;
+    /// The directory dmypy and mypy are run from: `working_dir`, or else the project root.
+    fn working_dir(&self) -> PathBuf {
+        self.working_dir_with(&self.config())
+    }
+
+    fn working_dir_with(&self, config: &DmypylsConfig) -> PathBuf {
+        let root_dir = self.root_dir();
+        match config.working_dir() {
+            Some(working_dir) => root_dir.join(working_dir),
+            None => root_dir,
         }
     }
-    async fn did_close(&self, _params: DidCloseTextDocumentParams) {
-        log::info!("did_close called");
+
+    /// The arguments naming files to check: relative to the project root when run from it, and
+    /// absolute otherwise.
+    fn file_args(&self, file_paths: &[RelPathBuf]) -> Vec<std::ffi::OsString> {
+        let relative = self.working_dir() == self.root_dir();
+        file_paths
+            .iter()
+            .map(|file_path| match relative {
+                true => file_path.as_os_str().to_os_string(),
+                false => file_path.absolute_path().into_os_string(),
+            })
+            .collect()
     }
-    async fn diagnostic(
-        &self,
-        _params: DocumentDiagnosticParams,
-    ) -> TowerResult<DocumentDiagnosticReportResult> {
-        log::trace!("[diagnostic] called");
-        Ok(DocumentDiagnosticReportResult::Report(
-            DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
-                related_documents: None,
-                full_document_diagnostic_report: FullDocumentDiagnosticReport::default(),
-            }),
-        ))
+
+    async fn dmypy_is_running(&self) -> Result<bool> {
+        let mut cmd = self.dmypy_command()?;
+        cmd.arg("status");
+        Ok(status_reports_running(self.runner.as_ref(), cmd).await)
     }
-    async fn shutdown(&self) -> TowerResult<()> {
-        log::info!("Shutting down dmypyls (stopping dmypy)");
-        log::info!("{:?}", self.config.command()?.arg("stop").output().ok());
+
+    /// Starts the dmypy daemon for the project root.
+    async fn start_dmypy(&self, context: &str) -> Result<std::process::Output> {
+        self.daemon_warm.store(false, Ordering::Relaxed);
+        let mut cmd = self.dmypy_command()?;
+        cmd.arg("run")
+            .arg("--")
+            .args(self.mypy_options(context))
+            .arg(self.root_dir());
+        let output = self.run_dmypy(&mut cmd).await?;
+        log::info!(
+            "[{context}] dympy run status: {:?} [stderr={}]",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        if output.status.success() {
+            *self.daemon_started.lock().unwrap() = Some(std::time::Instant::now());
+        }
+        Ok(output)
+    }
+
+    /// Starts the daemon if it isn't running, e.g. because it was stopped for being idle.
+    async fn ensure_dmypy_running(&self, context: &str) -> Result<()> {
+        if !self.config().uses_daemon() || self.dmypy_is_running().await? {
+            return Ok(());
+        }
+        log::info!("[{context}] dmypy is not running, starting it...");
+        let output = self.start_dmypy(context).await?;
+        if !output.status.success() {
+            return Err(crate::error::Error::daemon_not_running(format!(
+                "dmypy run {}",
+                describe_failure(&output)
+            )));
+        }
         Ok(())
     }
 
-    async fn hover(&self, params: HoverParams) -> TowerResult<Option<Hover>> {
-        log::info!("Hover called {params:?}");
-        let uri = params.text_document_position_params.text_document.uri;
-        let file_path = match PathBuf::from(uri.path()).canonicalize() {
-            Err(io_error) => {
-                return TowerResult::Err(tower_lsp::jsonrpc::Error {
-                    code: tower_lsp::jsonrpc::ErrorCode::InvalidParams,
-                    message: format!("No document found for url '{uri}': {io_error}").into(),
-                    data: None,
-                })
+    /// Records that the daemon is in use, restarting it if it was stopped for being idle.
+    async fn touch(&self, context: &str) {
+        *self.last_activity.lock().unwrap() = std::time::Instant::now();
+        if self.stopped_idle.swap(false, Ordering::SeqCst) {
+            if let Err(error) = self.ensure_dmypy_running(context).await {
+                log::error!("[{context}] Failed to restart dmypy: {error}");
+                // Starting the daemon may succeed next time, unless there's no command to start.
+                if error.kind() != ErrorKind::NoCommand {
+                    self.stopped_idle.store(true, Ordering::SeqCst);
+                }
             }
-            Ok(path) => path,
-        };
+        }
+    }
 
-        // Call `dmypy inspect`
-        let Some(output) = self
-            .config
-            .command()?
-            .arg("inspect")
-            .arg(file_path)
-            .output()
-            .ok_or_log("Failed to execute dmypy inspect")
-        else {
-            return Err(tower_lsp::jsonrpc::Error::internal_error());
-        };
+    /// Spawns a task that stops the daemon whenever nothing has used it for `idle_timeout_secs`.
+    /// The task ends once `idle_timeout_secs` is unset.
+    fn spawn_idle_shutdown(&self) {
+        let config = self.config.clone();
+        let root_dir = self.root_dir.clone();
+        let base_dirs = self.base_dirs.clone();
+        let runner = self.runner.clone();
+        let last_activity = self.last_activity.clone();
+        let stopped_idle = self.stopped_idle.clone();
+        tokio::spawn(async move {
+            loop {
+                let config = config.read().unwrap().clone();
+                let Some(timeout) = config.idle_timeout_secs.map(std::time::Duration::from_secs)
+                else {
+                    return;
+                };
+                let idle = last_activity.lock().unwrap().elapsed();
+                if idle < timeout {
+                    tokio::time::sleep(timeout - idle).await;
+                    continue;
+                }
+                if config.uses_daemon() && !stopped_idle.swap(true, Ordering::SeqCst) {
+                    log::info!("[idle_shutdown] stopping dmypy after {idle:?} without activity");
+                    let root_dir = root_dir.read().unwrap().clone();
+                    let status_file = default_status_file(&base_dirs, &root_dir);
+                    if let Some(mut cmd) = config
+                        .daemon_command(&root_dir, &status_file)
+                        .ok_or_log("Failed to build dmypy command")
+                    {
+                        runner
+                            .run(cmd.arg("stop"))
+                            .await
+                            .ok_or_log("Failed to stop idle dmypy");
+                    }
+                }
+                tokio::time::sleep(timeout).await;
+            }
+        });
+    }
 
-        if output.status.success() {
-            let inspect_output: Value = serde_json::from_slice(&output.stdout).unwrap();
-            // Construct hover response from inspect_output
-            let contents = HoverContents::Scalar(MarkedString::String(inspect_output.to_string()));
-            Ok(Some(Hover {
-                contents,
-                range: None,
-            }))
-        } else {
-            Ok(None)
+    /// The options passed to mypy, either when starting the daemon or in one-shot mode.
+    fn mypy_options(&self, context: &str) -> Vec<std::ffi::OsString> {
+        self.mypy_options_with(context, self.mypy_config())
+    }
+
+    /// Like `mypy_options`, passing `mypy_config` as the configuration file, if any.
+    fn mypy_options_with(
+        &self,
+        context: &str,
+        mypy_config: Option<PathBuf>,
+    ) -> Vec<std::ffi::OsString> {
+        let version = *self.mypy_version.read().unwrap();
+        // Lines without end positions are parsed by `MYPY_ERROR_REGEX_NO_END`.
+        let show_error_end = version.is_none_or(|version| version.supports_show_error_end());
+        let mut options: Vec<std::ffi::OsString> = MYPY_OUTPUT_FLAGS
+            .iter()
+            .filter(|&&flag| show_error_end || flag != "--show-error-end")
+            .map(|flag| flag.into())
+            .collect();
+        // Relative paths are resolved against the root rather than `working_dir`. A bare name like
+        // `python3` is left for mypy to look up on the `PATH`.
+        let python_executable = self.config().python_executable().map(|python_executable| {
+            match python_executable.components().count() > 1 {
+                true => self.root_dir().join(python_executable),
+                false => python_executable,
+            }
+        });
+        match python_executable {
+            Some(python_executable) => {
+                log::info!("[{context}] using python executable {python_executable:?}");
+                options.push("--python-executable".into());
+                options.push(python_executable.into());
+            }
+            None => log::info!("[{context}] using mypy's default python executable"),
+        }
+        match mypy_config {
+            Some(mypy_config) => {
+                log::info!("[{context}] using mypy configuration {mypy_config:?}");
+                options.push("--config-file".into());
+                options.push(mypy_config.into());
+            }
+            None => log::info!("[{context}] no mypy configuration found"),
         }
+        options.extend(self.config().extra_run_args.iter().map(Into::into));
+        options
     }
 
-    async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let uri = params.text_document.uri;
-        let version = params.text_document.version;
-        self.versions.lock().unwrap().insert(uri.clone(), version);
-        self.check_file("did_open", uri, version)
-            .await
-            .ok_or_log("Failed to check file");
+    /// The mypy configuration file, either configured (relative to the root) or found by
+    /// searching up from the root.
+    fn mypy_config(&self) -> Option<PathBuf> {
+        self.config()
+            .mypy_config()
+            .map(|mypy_config| self.root_dir().join(mypy_config))
+            .or_else(|| crate::mypy_config::find_mypy_config(&self.root_dir()))
     }
-    async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        log::info!("Did change called with {:?}", &params.text_document);
-        let uri = params.text_document.uri;
-        let version = params.text_document.version;
-        self.versions.lock().unwrap().insert(uri, version);
+
+    /// The compiled `exclude` patterns, from the configuration or else the mypy configuration.
+    fn exclude_patterns(&self) -> Vec<Regex> {
+        let patterns = match &self.config().exclude {
+            exclude if !exclude.is_empty() => exclude.clone(),
+            _ => self
+                .mypy_config()
+                .map(|mypy_config| crate::mypy_config::exclude_patterns(&mypy_config))
+                .unwrap_or_default(),
+        };
+        patterns
+            .iter()
+            .filter_map(|pattern| {
+                Regex::new(pattern).ok_or_log(&format!("Invalid exclude pattern {pattern:?}"))
+            })
+            .collect()
     }
-    async fn did_save(&self, params: DidSaveTextDocumentParams) {
-        let uri = params.text_document.uri;
-        // Assume it's ok to use the latest version.
-        let version = self
-            .versions
-            .lock()
-            .unwrap()
-            .get(&uri)
-            .cloned()
-            .unwrap_or(0);
 
-        self.check_file("did_save", uri, version)
-            .await
-            .ok_or_log("Failed to check file");
+    /// The command that checks the files appended to it: `dmypy check` or, in one-shot mode,
+    /// `mypy` itself.
+    fn check_command(&self) -> Result<Command> {
+        if self.config().uses_daemon() {
+            let mut cmd = self.dmypy_command()?;
+            cmd.arg("check");
+            Ok(cmd)
+        } else {
+            let mut cmd = self.config().mypy_command(&self.root_dir())?;
+            cmd.args(self.mypy_options("check_command"))
+                .current_dir(self.working_dir());
+            Ok(cmd)
+        }
     }
+
+    /// Checks the given files in a single invocation. Once the daemon is warm, this uses
+    /// `dmypy recheck --update`, which only re-analyzes what changed, falling back to `dmypy check`
+    /// if the recheck fails.
+    async fn check_files_output(
+        &self,
+        context: &str,
+        file_paths: &[RelPathBuf],
+    ) -> Result<std::process::Output> {
+        let _in_flight = InFlightCheck::new(self);
+        let _permit = self.check_permit().await;
+        let names: Vec<String> = file_paths.iter().map(ToString::to_string).collect();
+        let names = names.join(" ");
+        let recheck = self.config().uses_daemon() && self.daemon_warm.load(Ordering::Relaxed);
+        if recheck {
+            let mut cmd = self.dmypy_command()?;
+            cmd.args(["recheck", "--update"])
+                .args(self.file_args(file_paths));
+            let started = std::time::Instant::now();
+            let output = self
+                .run_dmypy(&mut cmd)
+                .await
+                .context("Failed to execute dmypy recheck")?;
+            log::info!(
+                target: "perf",
+                "[{context}] dmypy recheck of {names} took {:?}",
+                started.elapsed()
+            );
+            // mypy exits with 2 when it couldn't check at all, as opposed to finding errors.
+            if output.status.code() != Some(2) {
+                return Ok(output);
+            }
+            log::warn!(
+                "[{context}] dmypy recheck {}; falling back to dmypy check",
+                describe_failure(&output)
+            );
+        }
+        let mut cmd = self.check_command()?;
+        cmd.args(self.file_args(file_paths));
+        let started = std::time::Instant::now();
+        let output = self.run_check(context, &mut cmd).await?;
+        log::info!(
+            target: "perf",
+            "[{context}] dmypy check of {names} took {:?}",
+            started.elapsed()
+        );
+        Ok(output)
+    }
+
+    /// Runs a check command, retrying failures that are likely transient with exponential
+    /// backoff, up to `max_retries` times.
+    async fn run_check(&self, context: &str, cmd: &mut Command) -> Result<std::process::Output> {
+        log::info!("[{context}] running command: {:?}", cmd);
+        let mut attempt = 0;
+        let output = loop {
+            let retry = attempt < self.config().max_retries;
+            let failure = match self
+                .run_dmypy(cmd)
+                .await
+                .context("Failed to execute dmypy check")
+            {
+                Ok(output) if retry && is_transient_failure(&output) => describe_failure(&output),
+                Ok(output) => break output,
+                Err(error) => return Err(error),
+            };
+            let backoff = RETRY_BACKOFF * 2u32.pow(attempt);
+            attempt += 1;
+            log::warn!(
+                "[{context}] dmypy check {failure}; retrying in {backoff:?} (attempt {attempt})"
+            );
+            tokio::time::sleep(backoff).await;
+        };
+        if self.config().uses_daemon() && output.status.code() != Some(2) {
+            self.daemon_warm.store(true, Ordering::Relaxed);
+        }
+        Ok(output)
+    }
+
+    /// Runs a dmypy command, notifying the user if the configured command could not be found.
+    async fn run_dmypy(&self, cmd: &mut Command) -> std::io::Result<std::process::Output> {
+        log::info!(
+            "[run_dmypy] running {:?} [PWD={:?}]",
+            cmd.as_std().get_program(),
+            cmd.as_std().get_current_dir()
+        );
+        let output = self.runner.run(cmd).await;
+        if let Err(error) = &output {
+            self.notify_if_not_found(error).await;
+        }
+        output
+    }
+
+    /// A missing dmypy binary otherwise only shows up as an io error in the log file.
+    async fn notify_if_not_found(&self, error: &std::io::Error) {
+        if error.kind() != std::io::ErrorKind::NotFound {
+            return;
+        }
+        self.client
+            .show_message(
+                MessageType::ERROR,
+                format!(
+                    "dmypyls could not find the configured dmypy command `{}`. \
+                     Check dmypy_command in dmypyls.yaml.",
+                    self.config().resolve_command(&self.root_dir()).join(" ")
+                ),
+            )
+            .await;
+    }
+
+    /// Waits briefly for the daemon started by `initialize` to report that it is running.
+    async fn wait_for_daemon(&self, context: &str) {
+        if !self.config().uses_daemon() {
+            return;
+        }
+        for _ in 0..DAEMON_READY_ATTEMPTS {
+            if self.dmypy_is_running().await.unwrap_or(false) {
+                log::info!("[{context}] dmypy is running");
+                return;
+            }
+            tokio::time::sleep(DAEMON_READY_INTERVAL).await;
+        }
+        log::warn!("[{context}] dmypy is still not running, checking files anyway");
+    }
+
+    /// Switches to a new configuration, restarting the daemon if it would be started differently,
+    /// and re-checks all open files.
+    async fn replace_config(&self, context: &str, config: DmypylsConfig) -> Result<()> {
+        let old_config = self.config();
+        let root_dir = self.root_dir();
+        let restart = old_config.uses_daemon()
+            && (old_config.resolve_command(&root_dir) != config.resolve_command(&root_dir)
+                || old_config.python_executable() != config.python_executable()
+                || old_config.mypy_config() != config.mypy_config()
+                || old_config.status_file() != config.status_file()
+                || old_config.extra_run_args != config.extra_run_args);
+        if restart {
+            log::info!("[{context}] stopping dmypy to apply the new configuration");
+            self.runner
+                .run(self.dmypy_command_with(&old_config)?.arg("stop"))
+                .await
+                .ok_or_log("Failed to stop dmypy");
+        }
+        let spawn_idle_shutdown =
+            old_config.idle_timeout_secs.is_none() && config.idle_timeout_secs.is_some();
+        let detect_version = old_config.resolve_command(&root_dir)
+            != config.resolve_command(&root_dir)
+            || old_config.mypy_command != config.mypy_command
+            || old_config.mode != config.mode;
+        *self.config.write().unwrap() = Arc::new(config);
+        if detect_version {
+            self.detect_mypy_version(context).await;
+        }
+        if spawn_idle_shutdown {
+            self.spawn_idle_shutdown();
+        }
+        if restart && self.config().uses_daemon() {
+            let output = self.start_dmypy(context).await?;
+            if !output.status.success() {
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!("dmypy failed to start: {}", describe_failure(&output)),
+                    )
+                    .await;
+            }
+        }
+        let versions: Vec<(Url, i32)> = self.versions.lock().unwrap().clone().into_iter().collect();
+        for (uri, version) in versions {
+            self.check_file(context, uri, version)
+                .await
+                .ok_or_log("Failed to check file");
+        }
+        Ok(())
+    }
+
+    /// Stops and restarts the dmypy daemon, e.g. to recover from a bad state.
+    async fn restart_dmypy(&self, context: &str) -> Result<()> {
+        if !self.config().uses_daemon() {
+            return Err("dmypyls is running mypy in one-shot mode, without a daemon".into());
+        }
+        let output = self.runner.run(self.dmypy_command()?.arg("stop")).await?;
+        log::info!(
+            "[{context}] dmypy stop status: {:?} {}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout)
+        );
+        let output = self.start_dmypy(context).await?;
+        if !output.status.success() {
+            return Err(format!("dmypy run {}", describe_failure(&output)).into());
+        }
+        Ok(())
+    }
+
+    /// Asks dmypy (or mypy, in one-shot mode) for its version, so that flags it doesn't support
+    /// can be left out.
+    async fn detect_mypy_version(&self, context: &str) {
+        let config = self.config();
+        let cmd = if config.uses_daemon() {
+            self.dmypy_command()
+        } else {
+            config.mypy_command(&self.root_dir())
+        };
+        let version = match cmd {
+            Ok(mut cmd) => self
+                .runner
+                .run(cmd.arg("--version"))
+                .await
+                .ok()
+                .and_then(|output| {
+                    crate::version::MypyVersion::parse(&String::from_utf8_lossy(&output.stdout))
+                }),
+            Err(_) => None,
+        };
+        match version {
+            Some(version) => log::info!("[{context}] detected mypy version {version}"),
+            None => log::warn!("[{context}] could not detect the mypy version"),
+        }
+        *self.mypy_version.write().unwrap() = version;
+    }
+
+    /// Whether dmypy is recent enough to have the `inspect` subcommand.
+    async fn detect_inspect_support(&self, context: &str) -> bool {
+        if !self.config().uses_daemon() {
+            return false;
+        }
+        let Some(mut cmd) = self
+            .dmypy_command()
+            .ok_or_log("Failed to build dmypy command")
+        else {
+            return false;
+        };
+        let supported = self
+            .runner
+            .run(cmd.args(["inspect", "--help"]))
+            .await
+            .is_ok_and(|output| output.status.success());
+        log::info!("[{context}] dmypy inspect supported: {supported}");
+        supported
+    }
+
+    /// Runs `dmypy inspect` with the given arguments, returning its output if it succeeded.
+    async fn inspect(&self, args: &[&std::ffi::OsStr]) -> TowerResult<Option<String>> {
+        if !self.config().uses_daemon() || !self.supports_inspect.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let Some(output) = self
+            .runner
+            .run(self.dmypy_command()?.arg("inspect").args(args))
+            .await
+            .ok_or_log("Failed to execute dmypy inspect")
+        else {
+            return Err(tower_lsp::jsonrpc::Error::internal_error());
+        };
+        if !output.status.success() {
+            log::info!(
+                "[inspect] dmypy inspect failed: {}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+
+    /// Where the symbol at `location` is defined, if dmypy can tell.
+    async fn definition_of(&self, location: &str) -> TowerResult<Option<(PathBuf, Position)>> {
+        let Some(stdout) = self
+            .inspect(&["--show".as_ref(), "definition".as_ref(), location.as_ref()])
+            .await?
+        else {
+            return Ok(None);
+        };
+        Ok(crate::inspect::parse_definitions(&stdout)
+            .into_iter()
+            .next())
+    }
+
+    /// Where the symbols at each of `positions` in a file are defined, looked up concurrently.
+    /// Returns `None` if `dmypy inspect` is unavailable or fails to run for any of them.
+    async fn definitions_at(
+        &self,
+        file_path: &Path,
+        positions: &[Position],
+    ) -> Option<Vec<Option<(PathBuf, Position)>>> {
+        if !self.config().uses_daemon() || !self.supports_inspect.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut lookups = tokio::task::JoinSet::new();
+        for (index, &position) in positions.iter().enumerate() {
+            let mut cmd = self
+                .dmypy_command()
+                .ok_or_log("Failed to build dmypy command")?;
+            cmd.args(["inspect", "--show", "definition"])
+                .arg(crate::inspect::format_location(file_path, position));
+            let runner = self.runner.clone();
+            lookups.spawn(async move { (index, runner.run(&mut cmd).await) });
+        }
+        let mut definitions = vec![None; positions.len()];
+        while let Some(lookup) = lookups.join_next().await {
+            let (index, output) = lookup.ok_or_log("dmypy inspect task failed")?;
+            let output = output.ok_or_log("Failed to execute dmypy inspect")?;
+            if output.status.success() {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                definitions[index] = crate::inspect::parse_definitions(&stdout)
+                    .into_iter()
+                    .next();
+            }
+        }
+        Some(definitions)
+    }
+
+    /// The docstring of the function or class defined where the symbol at `location` is, if any.
+    async fn definition_docstring(&self, location: &str) -> TowerResult<Option<String>> {
+        let Some((path, position)) = self.definition_of(location).await? else {
+            return Ok(None);
+        };
+        let path = self.root_dir().join(path);
+        let text = Url::from_file_path(&path)
+            .ok()
+            .and_then(|uri| self.document_text(&uri))
+            .or_else(|| read_to_string(&path).ok());
+        Ok(text.and_then(|text| crate::inspect::docstring(&text, position.line)))
+    }
+
+    /// Reports the start of a long-running operation to the client, using the client-provided
+    /// token if there is one.
+    async fn begin_progress(
+        &self,
+        token: Option<ProgressToken>,
+        title: &str,
+    ) -> Option<ProgressToken> {
+        static NEXT_TOKEN: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let token = match token {
+            Some(token) => token,
+            None => {
+                let token = NumberOrString::String(format!(
+                    "dmypyls/{}",
+                    NEXT_TOKEN.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                ));
+                self.client
+                    .send_request::<request::WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+                        token: token.clone(),
+                    })
+                    .await
+                    .ok_or_log("Failed to create progress token")?;
+                token
+            }
+        };
+        self.client
+            .send_notification::<notification::Progress>(ProgressParams {
+                token: token.clone(),
+                value: ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(
+                    WorkDoneProgressBegin {
+                        title: title.to_string(),
+                        ..WorkDoneProgressBegin::default()
+                    },
+                )),
+            })
+            .await;
+        Some(token)
+    }
+
+    async fn end_progress(&self, token: Option<ProgressToken>) {
+        let Some(token) = token else {
+            return;
+        };
+        self.client
+            .send_notification::<notification::Progress>(ProgressParams {
+                token,
+                value: ProgressParamsValue::WorkDone(WorkDoneProgress::End(
+                    WorkDoneProgressEnd::default(),
+                )),
+            })
+            .await;
+    }
+
+    /// Pauses checking, clearing all diagnostics, or resumes it, re-checking open files.
+    async fn toggle_pause(&self, context: &str) {
+        let paused = !self.paused.fetch_xor(true, Ordering::SeqCst);
+        log::info!("[{context}] checking paused: {paused}");
+        if paused {
+            let uris: Vec<Url> = self.published.lock().unwrap().keys().cloned().collect();
+            let versions = self.versions.lock().unwrap().clone();
+            for uri in uris {
+                let version = versions.get(&uri).copied();
+                self.publish_diagnostics(uri, vec![], version).await;
+            }
+            self.client
+                .show_message(MessageType::INFO, "dmypy checking paused")
+                .await;
+        } else {
+            self.client
+                .show_message(MessageType::INFO, "dmypy checking resumed")
+                .await;
+            let files: Vec<(Url, i32)> =
+                self.versions.lock().unwrap().clone().into_iter().collect();
+            self.check_files(context, files)
+                .await
+                .ok_or_log("Failed to check open files");
+        }
+    }
+
+    /// Gathers a report on the environment for bug reports: the dmypy command and whether it can
+    /// be found, its version and status, and whether the status file can be written.
+    async fn self_test(&self) -> String {
+        let config = self.config();
+        let command = config.resolve_command(&self.root_dir());
+        let mut report = vec![
+            format!("dmypyls version: {}", env!("CARGO_PKG_VERSION")),
+            format!("root directory: {}", self.root_dir().display()),
+            format!("dmypy command: {}", command.join(" ")),
+        ];
+        let found = command
+            .first()
+            .and_then(|program| find_executable(&crate::config::expand_env_vars(program)));
+        report.push(match found {
+            Some(path) => format!("dmypy command found: {}", path.display()),
+            None => "dmypy command found: no".to_string(),
+        });
+        for subcommand in ["--version", "status"] {
+            let output = match self.dmypy_command() {
+                Ok(mut cmd) => self
+                    .runner
+                    .run(cmd.arg(subcommand))
+                    .await
+                    .map_err(Into::into),
+                Err(error) => Err(error),
+            };
+            report.push(match output {
+                Ok(output) => format!(
+                    "dmypy {subcommand}: {}",
+                    String::from_utf8_lossy(&output.stdout).trim()
+                ),
+                Err(error) => format!("dmypy {subcommand}: failed: {error}"),
+            });
+        }
+        let status_file = config
+            .status_file()
+            .unwrap_or_else(|| self.default_status_file());
+        let writable = status_file.parent().is_some_and(is_writable_dir);
+        report.push(format!(
+            "status file {} writable: {}",
+            status_file.display(),
+            if writable { "yes" } else { "no" }
+        ));
+        report.join("\n")
+    }
+
+    /// Summarizes the daemon's resource usage from `dmypy status --verbose`, falling back to plain
+    /// `dmypy status` for versions of dmypy without `--verbose`.
+    async fn daemon_stats(&self) -> Result<String> {
+        let mut cmd = self.dmypy_command()?;
+        let mut output = self.runner.run(cmd.args(["status", "--verbose"])).await?;
+        if output.status.code() == Some(2) {
+            log::info!(
+                "[daemon_stats] dmypy status --verbose {}",
+                describe_failure(&output)
+            );
+            let mut cmd = self.dmypy_command()?;
+            output = self.runner.run(cmd.arg("status")).await?;
+        }
+        if !output.status.success() {
+            return Err(format!("dmypy status {}", describe_failure(&output)).into());
+        }
+        let stats = parse_daemon_stats(&String::from_utf8_lossy(&output.stdout));
+        let uptime = match self.stopped_idle.load(Ordering::SeqCst) {
+            true => None,
+            false => self
+                .daemon_started
+                .lock()
+                .unwrap()
+                .map(|started| started.elapsed()),
+        };
+        Ok(format_daemon_stats(&stats, uptime))
+    }
+
+    /// The diagnostics last published for each file, as a JSON object keyed by uri.
+    fn published_diagnostics_json(&self) -> Value {
+        let published = self.published.lock().unwrap();
+        let files: std::collections::BTreeMap<String, Vec<&Diagnostic>> = published
+            .iter()
+            .map(|(uri, diagnostics)| {
+                let mut diagnostics: Vec<&Diagnostic> = diagnostics.iter().map(|d| &d.0).collect();
+                diagnostics
+                    .sort_by_key(|diagnostic| (diagnostic.range.start, diagnostic.range.end));
+                (uri.to_string(), diagnostics)
+            })
+            .collect();
+        serde_json::json!(files)
+    }
+
+    /// Writes the diagnostics last published for each file to a JSON file in the XDG state
+    /// directory, returning its path.
+    fn dump_diagnostics(&self) -> Result<PathBuf> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = self
+            .base_dirs
+            .place_state_file(format!("diagnostics-{timestamp}.json"))?;
+        let json = serde_json::to_string_pretty(&self.published_diagnostics_json())?;
+        std::fs::write(&path, json).context(&format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Waits until fewer than `max_concurrent_checks` checks are running, and counts the caller's
+    /// check among them until the permit is dropped.
+    async fn check_permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.check_permits
+            .acquire()
+            .await
+            .expect("the check semaphore is never closed")
+    }
+
+    /// Waits until no checks are running.
+    async fn wait_for_checks(&self) {
+        loop {
+            // Created before the count is read, so that a check finishing in between isn't missed.
+            let finished = self.check_finished.notified();
+            if self.checks_in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            finished.await;
+        }
+    }
+
+    /// Checks the whole project and publishes diagnostics for every file that has any.
+    async fn check_workspace(&self, context: &str) -> Result<()> {
+        if self.paused.load(Ordering::SeqCst) {
+            log::info!("[{context}] checking is paused");
+            return Ok(());
+        }
+        self.touch(context).await;
+        let _in_flight = InFlightCheck::new(self);
+        let root_dir = self.root_dir();
+        let mut cmd = self.check_command()?;
+        // The root itself rather than `.`, which would resolve against the server's cwd.
+        cmd.arg(&root_dir);
+        let permit = self.check_permit().await;
+        let output = self.run_check(context, &mut cmd).await?;
+        drop(permit);
+        let config = self.config();
+        // Open files without diagnostics are cleared, in case they previously had some.
+        let mut unreported: HashMap<Url, i32> = self.versions.lock().unwrap().clone();
+        let open_files: Vec<RelPathBuf> = unreported
+            .keys()
+            .filter_map(|uri| RelPathBuf::from_uri(root_dir.clone(), uri.clone()).ok())
+            .collect();
+        let files = check_diagnostics_by_file(context, &config, &root_dir, &open_files, &output)?;
+        let exclude_patterns = self.exclude_patterns();
+        for (filename, diagnostics) in files {
+            let Some(uri) = filename.to_uri().ok_or_log("Failed to publish diagnostics") else {
+                continue;
+            };
+            if !self.is_checked(&config, &exclude_patterns, &uri, &filename) {
+                log::info!("[{context}] not publishing diagnostics for {filename:?}");
+                continue;
+            }
+            let version = unreported.remove(&uri);
+            self.publish_check_result(context, uri, version, diagnostics)
+                .await;
+        }
+        for (uri, version) in unreported {
+            self.publish_diagnostics(uri, vec![], Some(version)).await;
+        }
+        Ok(())
+    }
+
+    /// Publishes diagnostics for a file, unless they are the same as those last published for it.
+    async fn publish_diagnostics(
+        &self,
+        uri: Url,
+        diagnostics: Vec<Diagnostic>,
+        version: Option<i32>,
+    ) {
+        let diagnostic_set = MypyLsDiagnostic::set_of(&diagnostics);
+        {
+            let mut published = self.published.lock().unwrap();
+            if published.get(&uri) == Some(&diagnostic_set) {
+                log::info!("[publish_diagnostics] diagnostics for {uri} are unchanged");
+                return;
+            }
+            published.insert(uri.clone(), diagnostic_set);
+        }
+        self.client
+            .publish_diagnostics(uri, diagnostics, version)
+            .await;
+    }
+
+    /// Drops everything known about a document, cancelling any check of it. Returns its version.
+    fn forget_document(&self, uri: &Url) -> Option<i32> {
+        let version = self.versions.lock().unwrap().remove(uri);
+        self.pending_checks.lock().unwrap().remove(uri);
+        if let Some(token) = self.cancellations.lock().unwrap().remove(uri) {
+            token.cancel();
+        }
+        self.documents.lock().unwrap().remove(uri);
+        self.inlay_hints.lock().unwrap().remove(uri);
+        self.revealed_types.lock().unwrap().remove(uri);
+        version
+    }
+
+    /// Clears the diagnostics of deleted files, and of the files under deleted directories.
+    async fn clear_deleted_files(&self, deleted: &[&Url]) {
+        let is_deleted = |uri: &Url| {
+            deleted.iter().any(|&deleted| {
+                uri == deleted
+                    || uri
+                        .as_str()
+                        .strip_prefix(deleted.as_str().trim_end_matches('/'))
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+        };
+        let mut uris: HashSet<Url> = deleted.iter().map(|&uri| uri.clone()).collect();
+        uris.extend(
+            self.published
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|uri| is_deleted(uri))
+                .cloned(),
+        );
+        for uri in uris {
+            let version = self.forget_document(&uri);
+            if self.published.lock().unwrap().remove(&uri).is_some() {
+                log::info!("[did_change_watched_files] clearing diagnostics of deleted {uri}");
+                self.client.publish_diagnostics(uri, vec![], version).await;
+            }
+        }
+    }
+
+    /// The latest text of an open document.
+    fn document_text(&self, uri: &Url) -> Option<String> {
+        self.documents.lock().unwrap().get(uri).cloned()
+    }
+
+    /// Converts a position from the client, in UTF-16 code units, into one counting characters
+    /// as dmypy does. Positions in documents that aren't open are assumed to be the same.
+    fn char_position(&self, uri: &Url, position: Position) -> Position {
+        match self.document_text(uri) {
+            Some(text) => crate::text::from_utf16_position(&text, position),
+            None => position,
+        }
+    }
+
+    /// Builds an inlay hint showing the inferred type of the name at `name`.
+    async fn type_hint(&self, file_path: &Path, name: Range) -> TowerResult<Option<InlayHint>> {
+        let location = crate::inspect::format_location(file_path, name.start);
+        let Some(stdout) = self
+            .inspect(&["--show".as_ref(), "type".as_ref(), location.as_ref()])
+            .await?
+        else {
+            return Ok(None);
+        };
+        let Some(inspection) = crate::inspect::parse_inspect_output(&stdout)
+            .ok_or_log(&format!("Failed to parse dmypy inspect output {stdout:?}"))
+            .and_then(|inspections| crate::inspect::select_inspection(inspections, name.start))
+        else {
+            return Ok(None);
+        };
+        Ok(Some(InlayHint {
+            position: name.end,
+            label: InlayHintLabel::String(format!(": {}", inspection.value)),
+            kind: Some(InlayHintKind::TYPE),
+            text_edits: None,
+            tooltip: None,
+            padding_left: None,
+            padding_right: None,
+            data: None,
+        }))
+    }
+
+    /// Offers to annotate the function defined on `line_number` with the signature inferred by
+    /// `dmypy suggest`.
+    async fn suggest_action(
+        &self,
+        uri: &Url,
+        text: &str,
+        line_number: u32,
+    ) -> Result<Option<CodeAction>> {
+        if !self.config().uses_daemon() {
+            return Ok(None);
+        }
+        let Some(line) = text.lines().nth(line_number as usize) else {
+            return Ok(None);
+        };
+        let Some((qualname, is_method)) =
+            crate::suggest::function_qualname(text, line_number as usize)
+        else {
+            return Ok(None);
+        };
+        let file_path = RelPathBuf::from_uri(self.root_dir(), uri.clone())?;
+        let Some(module) = file_path.to_module(&self.config().source_roots) else {
+            return Ok(None);
+        };
+        let mut cmd = self.dmypy_command()?;
+        cmd.arg("suggest").arg(format!("{module}.{qualname}"));
+        log::info!("[suggest] running command: {:?}", cmd);
+        let output = self
+            .run_dmypy(&mut cmd)
+            .await
+            .context("Failed to execute dmypy suggest")?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() {
+            log::info!(
+                "[suggest] dmypy suggest failed: {}",
+                describe_failure(&output)
+            );
+            return Ok(None);
+        }
+        let Some(new_line) = crate::suggest::parse_suggestion(&stdout)
+            .and_then(|suggestion| crate::suggest::annotate_def_line(line, &suggestion, is_method))
+        else {
+            log::info!("[suggest] no usable suggestion for {module}.{qualname}: {stdout}");
+            return Ok(None);
+        };
+        let edit = TextEdit {
+            range: Range {
+                start: Position {
+                    line: line_number,
+                    character: 0,
+                },
+                end: Position {
+                    line: line_number,
+                    character: line.encode_utf16().count() as u32,
+                },
+            },
+            new_text: new_line,
+        };
+        Ok(Some(CodeAction {
+            title: format!("Annotate {qualname} with the signature suggested by dmypy"),
+            kind: Some(CodeActionKind::REFACTOR_REWRITE),
+            edit: Some(WorkspaceEdit {
+                changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+                ..WorkspaceEdit::default()
+            }),
+            ..CodeAction::default()
+        }))
+    }
+
+    /// The contents of a file, read from the open document or else from disk.
+    fn file_text(&self, uri: &Url, file_path: &RelPathBuf) -> Option<String> {
+        self.document_text(uri)
+            .or_else(|| read_to_string(file_path.absolute_path()).ok())
+    }
+
+    /// Whether a file opts out of checking with a `# dmypyls: disable` directive.
+    fn is_disabled_by_directive(&self, uri: &Url, file_path: &RelPathBuf) -> bool {
+        self.file_text(uri, file_path)
+            .is_some_and(|text| crate::directive::is_disabled(&text))
+    }
+
+    /// Whether a file is one that dmypyls checks: a Python file under `include_paths` that isn't
+    /// excluded or disabled by a directive.
+    fn is_checked(
+        &self,
+        config: &DmypylsConfig,
+        exclude_patterns: &[Regex],
+        uri: &Url,
+        file_path: &RelPathBuf,
+    ) -> bool {
+        self.is_python_file(config, uri, file_path)
+            && config.is_included(file_path)
+            && !crate::mypy_config::is_excluded(exclude_patterns, file_path)
+            && !self.is_disabled_by_directive(uri, file_path)
+    }
+
+    /// Whether a file is Python: it has one of the configured `file_extensions`, or, with
+    /// `check_shebang_scripts`, it has no extension and starts with a Python shebang.
+    fn is_python_file(&self, config: &DmypylsConfig, uri: &Url, file_path: &RelPathBuf) -> bool {
+        match file_path.extension().and_then(|ext| ext.to_str()) {
+            Some(extension) => config.is_checked_extension(extension),
+            None => {
+                config.is_checked_extension("")
+                    || (config.check_shebang_scripts
+                        && self
+                            .file_text(uri, file_path)
+                            .is_some_and(|text| crate::directive::is_python_shebang(&text)))
+            }
+        }
+    }
+
+    /// Whether a newer version of the document than `version` has arrived.
+    fn is_stale(&self, uri: &Url, version: i32) -> bool {
+        self.versions
+            .lock()
+            .unwrap()
+            .get(uri)
+            .is_some_and(|&latest| latest > version)
+    }
+
+    /// Registers a new check of `uri`, cancelling the one already in flight, if any.
+    fn start_check(&self, uri: &Url) -> CancellationToken {
+        let token = CancellationToken::new();
+        if let Some(previous) = self
+            .cancellations
+            .lock()
+            .unwrap()
+            .insert(uri.clone(), token.clone())
+        {
+            previous.cancel();
+        }
+        token
+    }
+
+    /// Waits `debounce_ms`, returning whether the check requested for this version of the file is
+    /// still wanted, i.e. no other check was requested for the same file (and no newer version
+    /// arrived) in the meantime. The last request in a burst always runs, so exactly one set of
+    /// diagnostics is published for the latest version.
+    async fn debounce(&self, context: &str, uri: &Url, version: i32) -> bool {
+        let generation = {
+            let mut pending_checks = self.pending_checks.lock().unwrap();
+            let generation = pending_checks.entry(uri.clone()).or_default();
+            *generation += 1;
+            *generation
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(self.config().debounce_ms)).await;
+        let superseded = self.pending_checks.lock().unwrap().get(uri) != Some(&generation)
+            || self.is_stale(uri, version);
+        if superseded {
+            log::info!("[{context}] skipping superseded check for {uri}:{version}");
+        }
+        !superseded
+    }
+
+    /// Checks the file once `debounce` allows it.
+    async fn check_file_debounced(&self, context: &str, uri: Url, version: i32) -> Result<()> {
+        if !self.debounce(context, &uri, version).await {
+            return Ok(());
+        }
+        self.check_file(context, uri, version).await
+    }
+
+    async fn check_file(&self, context: &str, uri: Url, version: i32) -> Result<()> {
+        self.check_files(context, vec![(uri, version)]).await
+    }
+
+    /// Queues a file to be checked along with any other files opened within `COALESCE_WINDOW`,
+    /// so that opening many files at once results in a single dmypy invocation.
+    async fn check_file_coalesced(&self, context: &str, uri: Url, version: i32) -> Result<()> {
+        let is_first = {
+            let mut queued_checks = self.queued_checks.lock().unwrap();
+            queued_checks.push((uri, version));
+            queued_checks.len() == 1
+        };
+        // The first queued file's handler checks the whole batch.
+        if !is_first {
+            return Ok(());
+        }
+        tokio::time::sleep(COALESCE_WINDOW).await;
+        let files = std::mem::take(&mut *self.queued_checks.lock().unwrap());
+        self.check_files(context, files).await
+    }
+
+    /// Checks the given versions of files with a single dmypy invocation and publishes the
+    /// diagnostics for each of them.
+    async fn check_files(&self, context: &str, files: Vec<(Url, i32)>) -> Result<()> {
+        if self.paused.load(Ordering::SeqCst) {
+            log::info!("[{context}] checking is paused");
+            for (uri, version) in files {
+                self.publish_diagnostics(uri, vec![], Some(version)).await;
+            }
+            return Ok(());
+        }
+        // `initialized` checks every open file once the daemon is ready, including these.
+        if !self.ready.load(Ordering::SeqCst) {
+            log::info!("[{context}] deferring checks until the daemon is ready");
+            return Ok(());
+        }
+        self.touch(context).await;
+        let root_dir = self.root_dir();
+        let config = self.config();
+        let exclude_patterns = self.exclude_patterns();
+        let mut targets: Vec<(Url, i32)> = Vec::new();
+        let mut file_paths: Vec<RelPathBuf> = Vec::new();
+        let mut outside_root: Vec<(Url, i32, RelPathBuf)> = Vec::new();
+        for (uri, version) in files {
+            let (file_path, is_outside_root) =
+                match RelPathBuf::from_uri(root_dir.clone(), uri.clone()) {
+                    Ok(file_path) => (file_path, false),
+                    // A file uri that isn't under the root, e.g. a shared script opened on its own.
+                    Err(error) => match RelPathBuf::from_uri_in_parent(uri.clone()) {
+                        Ok(file_path) if config.check_files_outside_root => (file_path, true),
+                        Ok(_) => {
+                            log::info!(
+                                "[{context}] not checking {uri}, which is outside {root_dir:?}"
+                            );
+                            continue;
+                        }
+                        Err(_) => {
+                            log::error!("[{context}] Failed to check {uri}: {error}");
+                            continue;
+                        }
+                    },
+                };
+            if !self.is_python_file(&config, &uri, &file_path) {
+                log::info!("[{context}] ignoring non-Python file: {file_path:?}");
+                continue;
+            }
+            if self.is_disabled_by_directive(&uri, &file_path) {
+                log::info!("[{context}] ignoring file disabled by a directive: {file_path:?}");
+                self.publish_diagnostics(uri, vec![], Some(version)).await;
+                continue;
+            }
+            if crate::mypy_config::is_excluded(&exclude_patterns, &file_path) {
+                log::info!("[{context}] ignoring excluded file: {file_path:?}");
+                self.publish_diagnostics(uri, vec![], Some(version)).await;
+                continue;
+            }
+            if is_outside_root {
+                outside_root.push((uri, version, file_path));
+                continue;
+            }
+            if !config.is_included(&file_path) {
+                log::info!("[{context}] ignoring file outside include_paths: {file_path:?}");
+                self.publish_diagnostics(uri, vec![], Some(version)).await;
+                continue;
+            }
+            log::info!("[{context}] checking file {file_path}:{version}");
+            targets.push((uri, version));
+            file_paths.push(file_path);
+        }
+        for (uri, version, file_path) in outside_root {
+            self.check_outside_root(context, uri, version, file_path)
+                .await
+                .ok_or_log(&format!(
+                    "[{context}] Failed to check file outside the root"
+                ));
+        }
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let tokens: Vec<CancellationToken> = targets
+            .iter()
+            .map(|(uri, _)| self.start_check(uri))
+            .collect();
+        let title = match file_paths.as_slice() {
+            [file_path] => format!("dmypy checking {}…", file_path.display()),
+            file_paths => format!("dmypy checking {} files…", file_paths.len()),
+        };
+        let progress = if self.supports_progress.load(Ordering::Relaxed) {
+            self.begin_progress(None, &title).await
+        } else {
+            None
+        };
+        if tokens.iter().all(CancellationToken::is_cancelled) {
+            log::info!("[{context}] all checks were cancelled before running dmypy");
+            self.end_progress(progress).await;
+            return Ok(());
+        }
+        let output = self.check_files_output(context, &file_paths).await;
+        self.end_progress(progress).await;
+        let output = output?;
+        log::info!(
+            "[{context}] dmypy check succeeded: {:?}",
+            output.status.success()
+        );
+        log::info!(
+            "[{context}] dmypy check output: {}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        log::info!(
+            "[{context}] dmypy check stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let diagnostics_by_target =
+            check_diagnostics(context, &config, &root_dir, &file_paths, &output)?;
+        if config.reveal_type_as_hint {
+            self.store_revealed_types(&root_dir, &targets, &file_paths, &output.stdout);
+        }
+        for (((uri, version), diagnostics), token) in
+            targets.into_iter().zip(diagnostics_by_target).zip(tokens)
+        {
+            if token.is_cancelled() {
+                log::info!("[{context}] not publishing diagnostics of cancelled check of {uri}");
+                continue;
+            }
+            self.publish_check_result(context, uri, Some(version), diagnostics)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Replaces the revealed types of the checked files with those found in `output`.
+    fn store_revealed_types(
+        &self,
+        root_dir: &Path,
+        targets: &[(Url, i32)],
+        file_paths: &[RelPathBuf],
+        output: &[u8],
+    ) {
+        let revealed = parse_revealed_types(&self.config(), root_dir, output);
+        for ((uri, _), file_path) in targets.iter().zip(file_paths) {
+            let text = self.document_text(uri);
+            let types = revealed
+                .iter()
+                .filter(|(filename, _, _)| filename == file_path)
+                .map(|(_, range, value)| match &text {
+                    Some(text) => (crate::text::to_utf16_range(text, *range), value.clone()),
+                    None => (*range, value.clone()),
+                })
+                .collect();
+            self.revealed_types
+                .lock()
+                .unwrap()
+                .insert(uri.clone(), types);
+        }
+    }
+
+    /// The revealed type whose `reveal_type(...)` call spans `position`.
+    fn revealed_type_at(&self, uri: &Url, position: Position) -> Option<(Range, String)> {
+        self.revealed_types
+            .lock()
+            .unwrap()
+            .get(uri)?
+            .iter()
+            .find(|(range, _)| range.start <= position && position <= range.end)
+            .cloned()
+    }
+
+    /// Checks a file outside the project root by running mypy (not the project's daemon) from the
+    /// directory containing it.
+    async fn check_outside_root(
+        &self,
+        context: &str,
+        uri: Url,
+        version: i32,
+        file_path: RelPathBuf,
+    ) -> Result<()> {
+        let config = self.config();
+        let mut cmd = config.mypy_command(&self.root_dir())?;
+        // The project's mypy configuration is for its own files; mypy finds the file's own
+        // configuration from its directory.
+        cmd.args(self.mypy_options_with(context, None))
+            .arg(file_path.as_os_str())
+            .current_dir(file_path.root_dir());
+        let _in_flight = InFlightCheck::new(self);
+        let _permit = self.check_permit().await;
+        log::info!("[{context}] running command: {:?}", cmd);
+        let output = self
+            .run_dmypy(&mut cmd)
+            .await
+            .context("Failed to execute mypy")?;
+        let root_dir = file_path.root_dir().to_path_buf();
+        let diagnostics =
+            parse_diagnostics(context, &config, &root_dir, &[file_path], &output.stdout)?.remove(0);
+        self.publish_check_result(context, uri, Some(version), diagnostics)
+            .await;
+        Ok(())
+    }
+
+    /// Publishes the diagnostics found by checking a version of a file, unless it has since
+    /// changed.
+    async fn publish_check_result(
+        &self,
+        context: &str,
+        uri: Url,
+        version: Option<i32>,
+        diagnostics: Vec<Diagnostic>,
+    ) {
+        let diagnostics = self.finish_diagnostics(&uri, diagnostics);
+        log::info!("[{context}] diagnostics for {uri}: {:?}", diagnostics);
+        // The document may have changed while dmypy was running.
+        if let Some(version) = version.filter(|&version| self.is_stale(&uri, version)) {
+            log::info!("[{context}] not publishing diagnostics for stale version {uri}:{version}");
+            return;
+        }
+        let summary = self
+            .config()
+            .report_summary
+            .then(|| format!("{uri}: {}", summarize_diagnostics(&diagnostics)));
+        self.publish_diagnostics(uri, diagnostics, version).await;
+        if let Some(summary) = summary {
+            self.client.log_message(MessageType::INFO, summary).await;
+        }
+    }
+
+    /// Converts the diagnostics of a file to the client's UTF-16 columns, and limits them to
+    /// `max_diagnostics_per_file`.
+    fn finish_diagnostics(&self, uri: &Url, mut diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        let config = self.config();
+        if let Some(text) = self.document_text(uri) {
+            for diagnostic in &mut diagnostics {
+                let mut range = crate::text::clamp_range(&text, diagnostic.range);
+                if config.trim_diagnostic_ranges {
+                    range = crate::text::trim_range_end(&text, range);
+                }
+                diagnostic.range = crate::text::to_utf16_range(&text, range);
+                for related in diagnostic.related_information.iter_mut().flatten() {
+                    if related.location.uri == *uri {
+                        related.location.range =
+                            crate::text::to_utf16_range(&text, related.location.range);
+                    }
+                }
+            }
+        }
+        if let Some(max) = config.max_diagnostics_per_file {
+            limit_diagnostics(&mut diagnostics, max, &config.diagnostic_source);
+        }
+        diagnostics
+    }
+
+    /// Checks a file for the pull model (`textDocument/diagnostic`), returning its diagnostics
+    /// rather than publishing them.
+    async fn pull_diagnostics(&self, context: &str, uri: &Url) -> Result<Vec<Diagnostic>> {
+        if self.paused.load(Ordering::SeqCst) || !self.ready.load(Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+        let root_dir = self.root_dir();
+        let config = self.config();
+        let Some(file_path) = RelPathBuf::from_uri(root_dir.clone(), uri.clone())
+            .ok_or_log(&format!("[{context}] Failed to check {uri}"))
+        else {
+            return Ok(Vec::new());
+        };
+        if !self.is_checked(&config, &self.exclude_patterns(), uri, &file_path) {
+            log::info!("[{context}] not checking {file_path:?}");
+            return Ok(Vec::new());
+        }
+        self.touch(context).await;
+        let file_paths = [file_path];
+        let output = self.check_files_output(context, &file_paths).await?;
+        let diagnostics =
+            check_diagnostics(context, &config, &root_dir, &file_paths, &output)?.remove(0);
+        Ok(self.finish_diagnostics(uri, diagnostics))
+    }
+}
+
+/// The diagnostics of each of `targets` found by a check. A failure that produced no diagnostics
+/// at all (e.g. a broken mypy configuration) would otherwise look like a clean file, so it is
+/// reported as a diagnostic of its own when `surface_daemon_errors` is set.
+fn check_diagnostics(
+    context: &str,
+    config: &DmypylsConfig,
+    root_dir: &Path,
+    targets: &[RelPathBuf],
+    output: &std::process::Output,
+) -> Result<Vec<Vec<Diagnostic>>> {
+    let files = check_diagnostics_by_file(context, config, root_dir, targets, output)?;
+    Ok(diagnostics_by_target(targets, files))
+}
+
+/// The diagnostics of each file reported by a check. If the check failed without reporting any,
+/// each of `failing` gets the failure as a diagnostic instead, as in `check_diagnostics`.
+fn check_diagnostics_by_file(
+    context: &str,
+    config: &DmypylsConfig,
+    root_dir: &Path,
+    failing: &[RelPathBuf],
+    output: &std::process::Output,
+) -> Result<Vec<(RelPathBuf, Vec<Diagnostic>)>> {
+    let files = parse_diagnostics_by_file(context, config, root_dir, &output.stdout)?;
+    if !config.surface_daemon_errors || output.status.success() || !files.is_empty() {
+        return Ok(files);
+    }
+    let failure = failure_diagnostic(output, &config.diagnostic_source);
+    Ok(failing
+        .iter()
+        .map(|file_path| (file_path.clone(), vec![failure.clone()]))
+        .collect())
+}
+
+/// Counts diagnostics by severity, e.g. "2 errors, 1 warning".
+fn summarize_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    let counts: Vec<String> = [
+        (DiagnosticSeverity::ERROR, "error", "errors"),
+        (DiagnosticSeverity::WARNING, "warning", "warnings"),
+        (
+            DiagnosticSeverity::INFORMATION,
+            "information",
+            "information",
+        ),
+        (DiagnosticSeverity::HINT, "hint", "hints"),
+    ]
+    .into_iter()
+    .filter_map(|(severity, singular, plural)| {
+        let count = diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.severity.unwrap_or(DiagnosticSeverity::ERROR) == severity
+            })
+            .count();
+        match count {
+            0 => None,
+            1 => Some(format!("1 {singular}")),
+            _ => Some(format!("{count} {plural}")),
+        }
+    })
+    .collect();
+    match counts.is_empty() {
+        true => "no problems".to_string(),
+        false => counts.join(", "),
+    }
+}
+
+/// Keeps the topmost `max` diagnostics, followed by a note saying how many more were dropped.
+fn limit_diagnostics(diagnostics: &mut Vec<Diagnostic>, max: usize, source: &str) {
+    if diagnostics.len() <= max {
+        return;
+    }
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.range.start, diagnostic.range.end));
+    let suppressed = diagnostics.len() - max;
+    diagnostics.truncate(max);
+    diagnostics.push(Diagnostic {
+        range: Range::default(),
+        severity: Some(DiagnosticSeverity::INFORMATION),
+        source: Some(source.to_string()),
+        message: format!("{suppressed} more diagnostics suppressed"),
+        ..Default::default()
+    });
+}
+
+/// A status file under the XDG state directory that is unique to `root_dir`.
+fn default_status_file(base_dirs: &xdg::BaseDirectories, root_dir: &Path) -> PathBuf {
+    let mut hasher = std::hash::DefaultHasher::new();
+    std::hash::Hash::hash(root_dir, &mut hasher);
+    let file_name = format!("daemon-{:016x}.json", std::hash::Hasher::finish(&hasher));
+    base_dirs
+        .place_state_file(&file_name)
+        .unwrap_or_else(|_| base_dirs.get_state_home().join(file_name))
+}
+
+/// Runs `dmypy status`, which exits with 0 if and only if the daemon is running. The wording of
+/// its output is only consulted if the process didn't exit normally.
+async fn status_reports_running(runner: &dyn CommandRunner, mut status: Command) -> bool {
+    let Ok(output) = runner.run(&mut status).await else {
+        return false;
+    };
+    match output.status.code() {
+        Some(code) => code == 0,
+        None => String::from_utf8_lossy(&output.stdout)
+            .trim_start()
+            .starts_with("Daemon is up and running"),
+    }
+}
+
+/// Parses the `key : value` lines printed by `dmypy status --verbose`.
+fn parse_daemon_stats(stdout: &str) -> std::collections::BTreeMap<String, String> {
+    let re = Regex::new(r"^(?P<key>\w+)\s*:\s*(?P<value>.*?)\s*$").unwrap();
+    stdout
+        .lines()
+        .filter_map(|line| re.captures(line))
+        .map(|caps| (caps["key"].to_string(), caps["value"].to_string()))
+        .collect()
+}
+
+/// A human-readable summary of the daemon's memory use, module count and uptime, noting anything
+/// this version of dmypy doesn't report.
+fn format_daemon_stats(
+    stats: &std::collections::BTreeMap<String, String>,
+    uptime: Option<std::time::Duration>,
+) -> String {
+    let memory = match (stats.get("memory_rss_mib"), stats.get("memory_maxrss_mib")) {
+        (Some(rss), Some(maxrss)) => format!("{rss} MiB (peak {maxrss} MiB)"),
+        (Some(rss), None) => format!("{rss} MiB"),
+        _ if stats.contains_key("memory_psutil_missing") => {
+            "not available (psutil is not installed for the daemon)".to_string()
+        }
+        _ => "not reported by this version of dmypy".to_string(),
+    };
+    let modules = match stats.get("graph_size") {
+        Some(modules) => modules.clone(),
+        None => "not reported by this version of dmypy".to_string(),
+    };
+    let uptime = match uptime {
+        Some(uptime) => format!("{}s", uptime.as_secs()),
+        None => "unknown (the daemon was not started by this session)".to_string(),
+    };
+    let mut summary = vec![
+        format!("dmypy memory: {memory}"),
+        format!("modules: {modules}"),
+        format!("uptime: {uptime}"),
+    ];
+    if let Some(pid) = stats.get("pid") {
+        summary.push(format!("pid: {pid}"));
+    }
+    summary.join("\n")
+}
+
+/// A diagnostic at the top of a file reporting that dmypy failed.
+fn failure_diagnostic(output: &std::process::Output, source: &str) -> Diagnostic {
+    Diagnostic {
+        range: Range::default(),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some(source.to_string()),
+        message: format!("dmypy {}", describe_failure(output)),
+        ..Default::default()
+    }
+}
+
+/// Finds a program as the shell would: directly if it is a path, or else on the `PATH`.
+fn find_executable(program: &str) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let executable = if cfg!(windows) {
+        format!("{program}.exe")
+    } else {
+        program.to_string()
+    };
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(&executable))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Whether a file can be created in `dir`.
+fn is_writable_dir(dir: &Path) -> bool {
+    let probe = dir.join(format!(".dmypyls-self-test-{}", std::process::id()));
+    let writable = std::fs::write(&probe, "").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    writable
+}
+
+/// Whether a failed check is worth retrying: the daemon was busy or not yet accepting
+/// connections, as happens while it starts up.
+fn is_transient_failure(output: &std::process::Output) -> bool {
+    if output.status.success() {
+        return false;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    [stdout, stderr].iter().any(|text| {
+        text.contains("Daemon is busy")
+            || text.contains("Connection refused")
+            || text.contains("Resource temporarily unavailable")
+    })
+}
+
+/// Summarizes a failed dmypy invocation for the user, preferring its stderr.
+fn describe_failure(output: &std::process::Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let details = if stderr.trim().is_empty() {
+        String::from_utf8_lossy(&output.stdout)
+    } else {
+        stderr
+    };
+    format!("exited with {}: {}", output.status, details.trim())
+}
+
+/// Picks the project root from `root_uri`, falling back to `root_path` and then the current
+/// directory. A relative `root_path` is resolved against the current directory, so that the root
+/// is always absolute.
+#[allow(deprecated)]
+fn root_dir_from_params(params: &InitializeParams) -> PathBuf {
+    let current_dir = std::env::current_dir().unwrap();
+    params
+        .root_uri
+        .as_ref()
+        .and_then(|uri| uri.to_file_path().ok())
+        .or_else(|| params.root_path.as_ref().map(|path| current_dir.join(path)))
+        .unwrap_or(current_dir)
+}
+
+/// Resolves the on-disk path of a document, for passing to dmypy.
+fn document_path(uri: &Url) -> TowerResult<PathBuf> {
+    PathBuf::from(uri.path())
+        .canonicalize()
+        .map_err(|io_error| tower_lsp::jsonrpc::Error {
+            code: tower_lsp::jsonrpc::ErrorCode::InvalidParams,
+            message: format!("No document found for url '{uri}': {io_error}").into(),
+            data: None,
+        })
+}
+
+#[tower_lsp::async_trait]
+impl tower_lsp::LanguageServer for Backend {
+    async fn initialize(&self, params: InitializeParams) -> TowerResult<InitializeResult> {
+        log::info!("[initialize] Initializing dmypyls");
+        log::trace!(
+            "[initialize] client text document capabilities: {}",
+            serde_json::to_string(&params.capabilities.text_document).unwrap()
+        );
+        let supports_progress = params
+            .capabilities
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        self.supports_progress
+            .store(supports_progress, Ordering::Relaxed);
+        let root = root_dir_from_params(&params);
+        log::info!("[initialize] using root directory {root:?}");
+        *self.root_dir.write().unwrap() = root.clone();
+        // The configuration was read from the directory the server was started in, which need
+        // not be the project, so look again from the root as reloading does.
+        match read_config(&self.base_dirs, &root) {
+            Ok(config) => *self.config.write().unwrap() = Arc::new(config.unwrap_or_default()),
+            Err(error) => {
+                log::error!("[initialize] keeping the startup configuration: {error}");
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!("Failed to read {CONFIG_FILE_NAME}: {error}"),
+                    )
+                    .await;
+            }
+        }
+        self.detect_mypy_version("initialize").await;
+        if !self.config().uses_daemon() {
+            log::info!("[initialize] running mypy in one-shot mode, not starting dmypy");
+        } else if !self.dmypy_is_running().await? {
+            log::info!("[initialize] dmypy is not yet running, starting it...");
+            let output = self
+                .start_dmypy("initialize")
+                .await
+                .ok_or_log("Failed to start dmypy");
+            if let Some(output) = output.filter(|output| !output.status.success()) {
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!("dmypy failed to start: {}", describe_failure(&output)),
+                    )
+                    .await;
+            }
+        } else {
+            log::info!("[initialize] dmypy is already running");
+        }
+        let supports_inspect = self.detect_inspect_support("initialize").await;
+        self.supports_inspect
+            .store(supports_inspect, Ordering::Relaxed);
+
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::FULL,
+                )),
+                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
+                    DiagnosticOptions {
+                        identifier: None,
+                        inter_file_dependencies: self.config().recheck_dependents,
+                        workspace_diagnostics: false,
+                        work_done_progress_options: WorkDoneProgressOptions {
+                            work_done_progress: Some(true),
+                        },
+                    },
+                )),
+                // These all rely on `dmypy inspect`.
+                hover_provider: (self.config().enable_hover
+                    && (supports_inspect || self.config().reveal_type_as_hint))
+                    .then_some(HoverProviderCapability::Simple(true)),
+                definition_provider: (supports_inspect && self.config().enable_definition)
+                    .then_some(OneOf::Left(true)),
+                inlay_hint_provider: (supports_inspect && self.config().enable_inlay_hints)
+                    .then_some(OneOf::Left(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                // Falls back to matching text without `dmypy inspect`.
+                document_highlight_provider: Some(OneOf::Left(true)),
+                completion_provider: (supports_inspect && self.config().enable_completion).then(
+                    || CompletionOptions {
+                        trigger_characters: Some(vec![".".to_string()]),
+                        ..CompletionOptions::default()
+                    },
+                ),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        RESTART_DAEMON_COMMAND.to_string(),
+                        RECHECK_WORKSPACE_COMMAND.to_string(),
+                        TOGGLE_PAUSE_COMMAND.to_string(),
+                        SELF_TEST_COMMAND.to_string(),
+                        DUMP_DIAGNOSTICS_COMMAND.to_string(),
+                        DAEMON_STATS_COMMAND.to_string(),
+                    ],
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                }),
+                ..ServerCapabilities::default()
+            },
+            server_info: Some(ServerInfo {
+                name: "dmypyls".to_string(),
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            }),
+        })
+    }
+
+    async fn initialized(&self, _: InitializedParams) {
+        let config_watchers =
+            [CONFIG_FILE_NAME, PYPROJECT_FILE_NAME]
+                .into_iter()
+                .map(|file_name| FileSystemWatcher {
+                    glob_pattern: GlobPattern::String(format!("**/{file_name}")),
+                    kind: None,
+                });
+        // Deleted (or renamed) source files, whose diagnostics would otherwise linger.
+        let source_watchers = self
+            .config()
+            .file_extensions
+            .iter()
+            .map(|extension| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(format!("**/*.{extension}")),
+                kind: Some(WatchKind::Delete),
+            })
+            .collect::<Vec<_>>();
+        let watchers = DidChangeWatchedFilesRegistrationOptions {
+            watchers: config_watchers.chain(source_watchers).collect(),
+        };
+        let registration = Registration {
+            id: "dmypyls-watched-files".to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: serde_json::to_value(watchers).ok(),
+        };
+        if let Err(error) = self.client.register_capability(vec![registration]).await {
+            log::warn!("[initialized] failed to watch {CONFIG_FILE_NAME}: {error}");
+        }
+        self.wait_for_daemon("initialized").await;
+        self.ready.store(true, Ordering::SeqCst);
+        self.spawn_idle_shutdown();
+        if self.config().check_workspace_on_startup {
+            self.check_workspace("initialized")
+                .await
+                .ok_or_log("Failed to check workspace");
+        } else {
+            // Files opened while the daemon was starting up weren't checked.
+            let files: Vec<(Url, i32)> =
+                self.versions.lock().unwrap().clone().into_iter().collect();
+            self.check_files("initialized", files)
+                .await
+                .ok_or_log("Failed to check open files");
+        }
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> TowerResult<Option<Value>> {
+        log::info!("[execute_command] {}", params.command);
+        match params.command.as_str() {
+            RESTART_DAEMON_COMMAND => match self.restart_dmypy("execute_command").await {
+                Ok(()) => {
+                    self.client
+                        .show_message(MessageType::INFO, "dmypy daemon restarted")
+                        .await;
+                }
+                Err(error) => {
+                    log::error!("[execute_command] failed to restart dmypy: {error}");
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("Failed to restart dmypy daemon: {error}"),
+                        )
+                        .await;
+                }
+            },
+            RECHECK_WORKSPACE_COMMAND => {
+                let token = self
+                    .begin_progress(
+                        params.work_done_progress_params.work_done_token,
+                        "dmypy checking workspace…",
+                    )
+                    .await;
+                let result = self.check_workspace("execute_command").await;
+                self.end_progress(token).await;
+                if let Err(error) = result {
+                    log::error!("[execute_command] failed to recheck workspace: {error}");
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("Failed to recheck workspace: {error}"),
+                        )
+                        .await;
+                }
+            }
+            TOGGLE_PAUSE_COMMAND => self.toggle_pause("execute_command").await,
+            SELF_TEST_COMMAND => {
+                let report = self.self_test().await;
+                log::info!("[execute_command] self test:\n{report}");
+                self.client.show_message(MessageType::INFO, &report).await;
+                return Ok(Some(Value::String(report)));
+            }
+            DAEMON_STATS_COMMAND => match self.daemon_stats().await {
+                Ok(summary) => {
+                    self.client.show_message(MessageType::INFO, &summary).await;
+                    return Ok(Some(Value::String(summary)));
+                }
+                Err(error) => {
+                    log::error!("[execute_command] failed to get daemon stats: {error}");
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("Failed to get dmypy daemon stats: {error}"),
+                        )
+                        .await;
+                }
+            },
+            DUMP_DIAGNOSTICS_COMMAND => match self.dump_diagnostics() {
+                Ok(path) => {
+                    let path = path.display().to_string();
+                    self.client
+                        .show_message(MessageType::INFO, format!("Diagnostics written to {path}"))
+                        .await;
+                    return Ok(Some(Value::String(path)));
+                }
+                Err(error) => {
+                    log::error!("[execute_command] failed to dump diagnostics: {error}");
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("Failed to dump diagnostics: {error}"),
+                        )
+                        .await;
+                }
+            },
+            command => {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                    "Unknown command: {command}"
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn did_change_configuration(&self, dccp: DidChangeConfigurationParams) {
+        log::info!("did_change_configuration called");
+        if dccp.settings.is_null() {
+            return;
+        }
+        let Some((config, changed)) = self
+            .config()
+            .apply_settings(&dccp.settings)
+            .ok_or_log("Failed to apply settings")
+        else {
+            return;
+        };
+        log::info!("[did_change_configuration] changed settings: {changed:?}");
+        if changed.is_empty() {
+            return;
+        }
+        self.replace_config("did_change_configuration", config)
+            .await
+            .ok_or_log("Failed to apply settings");
+    }
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        let deleted: Vec<&Url> = params
+            .changes
+            .iter()
+            .filter(|change| change.typ == FileChangeType::DELETED)
+            .map(|change| &change.uri)
+            .collect();
+        if !deleted.is_empty() {
+            self.clear_deleted_files(&deleted).await;
+        }
+        let config_changed = params.changes.iter().any(|change| {
+            matches!(
+                change
+                    .uri
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back()),
+                Some(CONFIG_FILE_NAME | PYPROJECT_FILE_NAME)
+            )
+        });
+        if !config_changed {
+            return;
+        }
+        log::info!("[did_change_watched_files] reloading configuration");
+        let config = match read_config(&self.base_dirs, &self.root_dir()) {
+            Ok(config) => config.unwrap_or_default(),
+            Err(error) => {
+                log::error!("[did_change_watched_files] keeping the old configuration: {error}");
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!("Failed to reload {CONFIG_FILE_NAME}: {error}"),
+                    )
+                    .await;
+                return;
+            }
+        };
+        self.replace_config("did_change_watched_files", config)
+            .await
+            .ok_or_log("Failed to apply the reloaded configuration");
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        log::info!("did_close called");
+        let uri = params.text_document.uri;
+        let version = self.forget_document(&uri);
+        if self.config().clear_diagnostics_on_close {
+            self.published.lock().unwrap().remove(&uri);
+            self.client.publish_diagnostics(uri, vec![], version).await;
+        }
+    }
+    /// Diagnostics for the pull model. The result id is the document version, so a client asking
+    /// again about an unchanged document gets an `Unchanged` report without a new check.
+    async fn diagnostic(
+        &self,
+        params: DocumentDiagnosticParams,
+    ) -> TowerResult<DocumentDiagnosticReportResult> {
+        log::trace!("[diagnostic] called");
+        let uri = params.text_document.uri;
+        let version = self.versions.lock().unwrap().get(&uri).copied();
+        let result_id = version.map(|version| version.to_string());
+        if let Some(result_id) = result_id
+            .clone()
+            .filter(|result_id| params.previous_result_id.as_ref() == Some(result_id))
+        {
+            return Ok(DocumentDiagnosticReportResult::Report(
+                DocumentDiagnosticReport::Unchanged(RelatedUnchangedDocumentDiagnosticReport {
+                    related_documents: None,
+                    unchanged_document_diagnostic_report: UnchangedDocumentDiagnosticReport {
+                        result_id,
+                    },
+                }),
+            ));
+        }
+        let items = self.pull_diagnostics("diagnostic", &uri).await?;
+        Ok(DocumentDiagnosticReportResult::Report(
+            DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
+                related_documents: None,
+                full_document_diagnostic_report: FullDocumentDiagnosticReport { result_id, items },
+            }),
+        ))
+    }
+    async fn shutdown(&self) -> TowerResult<()> {
+        // Checks still running would otherwise fail against the stopped daemon.
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.wait_for_checks())
+            .await
+            .is_err()
+        {
+            log::warn!("[shutdown] gave up waiting for running checks after {SHUTDOWN_TIMEOUT:?}");
+        }
+        if !self.config().uses_daemon() {
+            log::info!("Shutting down dmypyls");
+            return Ok(());
+        }
+        log::info!("Shutting down dmypyls (stopping dmypy)");
+        log::info!(
+            "{:?}",
+            self.runner
+                .run(self.dmypy_command()?.arg("stop"))
+                .await
+                .ok()
+        );
+        Ok(())
+    }
+
+    async fn hover(&self, params: HoverParams) -> TowerResult<Option<Hover>> {
+        log::info!("Hover called {params:?}");
+        if !self.config().enable_hover {
+            return Ok(None);
+        }
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        if let Some((range, value)) = self.revealed_type_at(&uri, position) {
+            return Ok(Some(Hover {
+                contents: HoverContents::Scalar(MarkedString::LanguageString(LanguageString {
+                    language: "python".to_string(),
+                    value,
+                })),
+                range: Some(range),
+            }));
+        }
+        if !self.supports_inspect.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        self.touch("hover").await;
+        let file_path = document_path(&uri)?;
+        let position = self.char_position(&uri, position);
+        let location = crate::inspect::format_location(&file_path, position);
+
+        let Some(stdout) = self
+            .inspect(&["--include-span".as_ref(), location.as_ref()])
+            .await?
+        else {
+            return Ok(None);
+        };
+        let Some(inspections) = crate::inspect::parse_inspect_output(&stdout)
+            .ok_or_log(&format!("Failed to parse dmypy inspect output {stdout:?}"))
+        else {
+            return Ok(None);
+        };
+        let Some(inspection) = crate::inspect::select_inspection(inspections, position) else {
+            return Ok(None);
+        };
+        let contents = match self.definition_docstring(&location).await? {
+            Some(docstring) => HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```python\n{}\n```\n\n{docstring}", inspection.value),
+            }),
+            None => HoverContents::Scalar(MarkedString::LanguageString(LanguageString {
+                language: "python".to_string(),
+                value: inspection.value,
+            })),
+        };
+        let range = match self.document_text(&uri) {
+            Some(text) => inspection
+                .range
+                .map(|range| crate::text::to_utf16_range(&text, range)),
+            None => inspection.range,
+        };
+        Ok(Some(Hover { contents, range }))
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> TowerResult<Option<GotoDefinitionResponse>> {
+        log::info!("goto_definition called {params:?}");
+        if !self.config().enable_definition || !self.supports_inspect.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let file_path = document_path(&uri)?;
+        let position = self.char_position(&uri, position);
+        let location = crate::inspect::format_location(&file_path, position);
+        let Some((path, position)) = self.definition_of(&location).await? else {
+            return Ok(None);
+        };
+        let path = self.root_dir().join(path);
+        let Ok(uri) = Url::from_file_path(&path) else {
+            log::warn!("[goto_definition] cannot convert {path:?} to a uri");
+            return Ok(None);
+        };
+        let text = self
+            .document_text(&uri)
+            .or_else(|| read_to_string(&path).ok());
+        let position = match text {
+            Some(text) => crate::text::to_utf16_position(&text, position),
+            None => position,
+        };
+        Ok(Some(GotoDefinitionResponse::Scalar(Location {
+            uri,
+            range: Range {
+                start: position,
+                end: position,
+            },
+        })))
+    }
+
+    /// Highlights the occurrences of the identifier under the cursor. With `dmypy inspect`, only
+    /// those resolving to the same definition are kept; otherwise every textual match is.
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> TowerResult<Option<Vec<DocumentHighlight>>> {
+        log::trace!("[document_highlight] called");
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let Some(text) = self.document_text(&uri) else {
+            return Ok(None);
+        };
+        let position = crate::text::from_utf16_position(&text, position);
+        let Some((name, cursor)) = crate::inspect::identifier_at(&text, position) else {
+            return Ok(None);
+        };
+        let mut occurrences = crate::inspect::identifier_occurrences(&text, &name);
+        let file_path = document_path(&uri).ok();
+        if let Some(file_path) =
+            file_path.filter(|_| occurrences.len() <= MAX_HIGHLIGHT_INSPECTIONS)
+        {
+            let starts: Vec<Position> = occurrences.iter().map(|range| range.start).collect();
+            let definitions = self.definitions_at(&file_path, &starts).await;
+            let cursor_definition = definitions.as_ref().and_then(|definitions| {
+                let index = occurrences.iter().position(|&range| range == cursor)?;
+                definitions[index].clone()
+            });
+            if let (Some(definitions), Some(cursor_definition)) = (definitions, cursor_definition) {
+                occurrences = occurrences
+                    .into_iter()
+                    .zip(definitions)
+                    .filter(|(_, definition)| definition.as_ref() == Some(&cursor_definition))
+                    .map(|(occurrence, _)| occurrence)
+                    .collect();
+            }
+        }
+        Ok(Some(
+            occurrences
+                .into_iter()
+                .map(|range| DocumentHighlight {
+                    range: crate::text::to_utf16_range(&text, range),
+                    kind: Some(DocumentHighlightKind::TEXT),
+                })
+                .collect(),
+        ))
+    }
+
+    async fn completion(
+        &self,
+        params: CompletionParams,
+    ) -> TowerResult<Option<CompletionResponse>> {
+        log::info!("completion called {params:?}");
+        if !self.config().enable_completion {
+            return Ok(None);
+        }
+        let uri = params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        let Some(target) = self.document_text(&uri).and_then(|text| {
+            let position = crate::text::from_utf16_position(&text, position);
+            crate::inspect::completion_target(&text, position)
+        }) else {
+            return Ok(None);
+        };
+        let file_path = document_path(&uri)?;
+        let location = crate::inspect::format_location(&file_path, target);
+
+        let Some(stdout) = self
+            .inspect(&["--show".as_ref(), "attrs".as_ref(), location.as_ref()])
+            .await?
+        else {
+            return Ok(None);
+        };
+        let Some(attrs) = crate::inspect::parse_attrs(&stdout)
+            .ok_or_log(&format!("Failed to parse dmypy inspect output {stdout:?}"))
+        else {
+            return Ok(None);
+        };
+        if attrs.is_empty() {
+            return Ok(None);
+        }
+        let items = attrs
+            .into_iter()
+            .map(|(class, name)| CompletionItem {
+                // dmypy only reports attribute names, so methods can't be told apart from fields.
+                kind: Some(CompletionItemKind::FIELD),
+                detail: Some(class),
+                label: name,
+                ..CompletionItem::default()
+            })
+            .collect();
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+
+    async fn code_action(
+        &self,
+        params: CodeActionParams,
+    ) -> TowerResult<Option<CodeActionResponse>> {
+        log::info!("code_action called {params:?}");
+        let uri = params.text_document.uri;
+        let Some(text) = self.document_text(&uri) else {
+            return Ok(None);
+        };
+        let mut actions = CodeActionResponse::new();
+        for diagnostic in params.context.diagnostics {
+            let Some(NumberOrString::String(code)) = &diagnostic.code else {
+                continue;
+            };
+            if diagnostic.range.end < params.range.start
+                || params.range.end < diagnostic.range.start
+            {
+                continue;
+            }
+            let line_number = diagnostic.range.start.line;
+            let Some(line) = text.lines().nth(line_number as usize) else {
+                continue;
+            };
+            let Some(new_line) = crate::type_ignore::add_type_ignore(line, code) else {
+                continue;
+            };
+            let edit = TextEdit {
+                range: Range {
+                    start: Position {
+                        line: line_number,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: line_number,
+                        character: line.encode_utf16().count() as u32,
+                    },
+                },
+                new_text: new_line,
+            };
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Ignore mypy error [{code}] on this line"),
+                kind: Some(CodeActionKind::QUICKFIX),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+                    ..WorkspaceEdit::default()
+                }),
+                diagnostics: Some(vec![diagnostic]),
+                ..CodeAction::default()
+            }));
+        }
+        if self.config().enable_suggest {
+            let line_number = params.range.start.line;
+            if let Some(Some(action)) = self
+                .suggest_action(&uri, &text, line_number)
+                .await
+                .ok_or_log("Failed to suggest a signature")
+            {
+                actions.push(CodeActionOrCommand::CodeAction(action));
+            }
+        }
+        Ok(Some(actions))
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> TowerResult<Option<Vec<InlayHint>>> {
+        log::info!("inlay_hint called {params:?}");
+        if !self.config().enable_inlay_hints {
+            return Ok(None);
+        }
+        let uri = params.text_document.uri;
+        let Some(text) = self.document_text(&uri) else {
+            return Ok(None);
+        };
+        let version = self
+            .versions
+            .lock()
+            .unwrap()
+            .get(&uri)
+            .cloned()
+            .unwrap_or(0);
+        let file_path = document_path(&uri)?;
+        let mut hints = Vec::new();
+        for name in crate::inspect::unannotated_assignments(&text, params.range) {
+            let cached = self
+                .inlay_hints
+                .lock()
+                .unwrap()
+                .get(&uri)
+                .filter(|(cached_version, _)| *cached_version == version)
+                .and_then(|(_, cached)| {
+                    cached
+                        .get(&(name.start.line, name.start.character))
+                        .cloned()
+                });
+            let hint = match cached {
+                Some(hint) => hint,
+                None => {
+                    let hint = self.type_hint(&file_path, name).await?;
+                    let mut inlay_hints = self.inlay_hints.lock().unwrap();
+                    let entry = inlay_hints
+                        .entry(uri.clone())
+                        .or_insert_with(|| (version, HashMap::new()));
+                    if entry.0 != version {
+                        *entry = (version, HashMap::new());
+                    }
+                    entry
+                        .1
+                        .insert((name.start.line, name.start.character), hint.clone());
+                    hint
+                }
+            };
+            hints.extend(hint);
+        }
+        Ok(Some(hints))
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let uri = params.text_document.uri;
+        let version = params.text_document.version;
+        self.versions.lock().unwrap().insert(uri.clone(), version);
+        self.documents.lock().unwrap().insert(
+            uri.clone(),
+            crate::text::normalize_line_endings(&params.text_document.text),
+        );
+        self.check_file_coalesced("did_open", uri, version)
+            .await
+            .ok_or_log("Failed to check file");
+    }
+    /// When `check_on_change` is enabled, changes trigger a (debounced) check. dmypy only reads
+    /// files from disk, and checking a copy of the buffer in a temp location would confuse module
+    /// resolution, so the check runs against the last saved contents of the file. This keeps
+    /// diagnostics fresh for editors that autosave or when files are rewritten by other tools, at
+    /// the cost of lagging behind unsaved edits.
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        log::info!("Did change called with {:?}", &params.text_document);
+        let uri = params.text_document.uri;
+        let version = params.text_document.version;
+        self.versions.lock().unwrap().insert(uri.clone(), version);
+        // With full sync, each change holds the whole document, so the last one is current.
+        match params.content_changes.into_iter().next_back() {
+            Some(change) => {
+                self.documents.lock().unwrap().insert(
+                    uri.clone(),
+                    crate::text::normalize_line_endings(&change.text),
+                );
+            }
+            None => log::info!("[did_change] no content changes for {uri}:{version}"),
+        }
+        if self.config().check_on_change {
+            self.check_file_debounced("did_change", uri, version)
+                .await
+                .ok_or_log("Failed to check file");
+        }
+    }
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let uri = params.text_document.uri;
+        // Assume it's ok to use the latest version.
+        let version = self
+            .versions
+            .lock()
+            .unwrap()
+            .get(&uri)
+            .cloned()
+            .unwrap_or(0);
+
+        if !self.debounce("did_save", &uri, version).await {
+            return;
+        }
+        if self.config().recheck_dependents {
+            self.check_workspace("did_save")
+                .await
+                .ok_or_log("Failed to check workspace");
+        } else {
+            self.check_file("did_save", uri, version)
+                .await
+                .ok_or_log("Failed to check file");
+        }
+    }
+}
+
+#[test]
+fn test_find_project_config() {
+    let dir = test_dir("find-config");
+    let nested = dir.join("repo/pkg/sub");
+    std::fs::create_dir_all(&nested).unwrap();
+    assert_eq!(find_project_config(&nested, CONFIG_FILE_NAME), None);
+    std::fs::write(dir.join(CONFIG_FILE_NAME), "{}").unwrap();
+    assert_eq!(
+        find_project_config(&nested, CONFIG_FILE_NAME),
+        Some(dir.join(CONFIG_FILE_NAME))
+    );
+    // The search stops at the root of the repository.
+    std::fs::create_dir(dir.join("repo/.git")).unwrap();
+    assert_eq!(find_project_config(&nested, CONFIG_FILE_NAME), None);
+    std::fs::write(dir.join("repo/pkg").join(CONFIG_FILE_NAME), "{}").unwrap();
+    assert_eq!(
+        find_project_config(&nested, CONFIG_FILE_NAME),
+        Some(dir.join("repo/pkg").join(CONFIG_FILE_NAME))
+    );
+    assert_eq!(find_project_config(&nested, PYPROJECT_FILE_NAME), None);
+    std::fs::write(
+        dir.join("repo").join(PYPROJECT_FILE_NAME),
+        "[tool.dmypyls]\ndebounce_ms = 10\n",
+    )
+    .unwrap();
+    let pyproject_file = find_project_config(&nested, PYPROJECT_FILE_NAME).unwrap();
+    let config = read_config_from_pyproject(&pyproject_file)
+        .unwrap()
+        .unwrap();
+    assert_eq!(config.debounce_ms, 10);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_mypy_severity() {
+    assert_eq!(mypy_severity("error"), DiagnosticSeverity::ERROR);
+    assert_eq!(mypy_severity("warning"), DiagnosticSeverity::WARNING);
+    assert_eq!(mypy_severity("note"), DiagnosticSeverity::INFORMATION);
+    assert_eq!(mypy_severity("fatal"), DiagnosticSeverity::INFORMATION);
+}
+
+#[test]
+fn test_parse_diagnostics_source() {
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output =
+        b"/project/foo.py:3:5:3:10: error: Incompatible types in assignment  [assignment]\n";
+    let config = crate::config::parse_config("{}").unwrap();
+    let targets = [target];
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output).unwrap();
+    assert_eq!(diagnostics[0][0].source.as_deref(), Some("dmypy"));
+    let config = crate::config::parse_config(r#"{ "diagnostic_source": "mypy" }"#).unwrap();
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output).unwrap();
+    assert_eq!(diagnostics[0][0].source.as_deref(), Some("mypy"));
+}
+
+#[test]
+fn test_parse_diagnostics_severity() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output = b"/project/foo.py:1:1:1:5: note: Standalone note
+/project/foo.py:3:5:3:10: error: Incompatible types in assignment  [assignment]
+";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    let severities: Vec<_> = diagnostics.iter().map(|d| d.severity).collect();
+    assert_eq!(
+        severities,
+        [
+            Some(DiagnosticSeverity::HINT),
+            Some(DiagnosticSeverity::ERROR)
+        ]
+    );
+}
+
+#[test]
+fn test_parse_diagnostics_note_severity() {
+    let root_dir = Path::new("/project");
+    let targets = [RelPathBuf::from_filename(root_dir, "foo.py").unwrap()];
+    let output = b"/project/foo.py:1:1:1:5: note: Standalone note\n";
+    let severity = |config: &str| {
+        let config = crate::config::parse_config(config).unwrap();
+        parse_diagnostics("test", &config, root_dir, &targets, output).unwrap()[0][0].severity
+    };
+    assert_eq!(
+        severity(r#"{ "dmypy_command": ["dmypy"], "note_severity": "hint" }"#),
+        Some(DiagnosticSeverity::HINT)
+    );
+    assert_eq!(
+        severity(
+            r#"{ "dmypy_command": ["dmypy"], "note_severity": "hint", "severity_overrides": { "note": "warning" } }"#
+        ),
+        Some(DiagnosticSeverity::WARNING)
+    );
+}
+
+#[cfg(windows)]
+#[test]
+fn test_parse_diagnostics_drive_letter() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new(r"C:\project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output = br#"c:\project\foo.py:10:5:10:9: error: Name "x" is not defined  [name-defined]
+C:\project\foo.py:11:1: error: Name "y" is not defined  [name-defined]
+"#;
+    let mut diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].range.start, Position::new(9, 4));
+    assert_eq!(diagnostics[0].range.end, Position::new(9, 8));
+    assert_eq!(diagnostics[1].range.start, Position::new(10, 0));
+}
+
+#[test]
+fn test_parse_diagnostics_error_code() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output =
+        b"/project/foo.py:3:5:3:10: error: Incompatible types in assignment  [assignment]\n";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].code,
+        Some(NumberOrString::String("assignment".to_string()))
+    );
+    assert_eq!(diagnostics[0].message, "Incompatible types in assignment");
+    assert_eq!(
+        diagnostics[0]
+            .code_description
+            .as_ref()
+            .map(|description| description.href.as_str()),
+        Some("https://mypy.readthedocs.io/en/stable/error_code_list.html#code-assignment")
+    );
+}
+
+#[test]
+fn test_parse_diagnostics_sorted() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let targets = [RelPathBuf::from_filename(root_dir, "foo.py").unwrap()];
+    let output = b"/project/foo.py:9:1:9:2: error: Last  [misc]
+/project/foo.py:2:5:2:6: error: Second  [misc]
+/project/foo.py:2:1:2:2: error: First b  [misc]
+/project/foo.py:2:1:2:2: error: First a  [misc]
+";
+    for _ in 0..10 {
+        let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output)
+            .unwrap()
+            .remove(0);
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["First a", "First b", "Second", "Last"]);
+    }
+}
+
+#[test]
+fn test_parse_diagnostics_ignore_codes() {
+    let config = crate::config::parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "ignore_codes": ["no-untyped-def"] }"#,
+    )
+    .unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output =
+        b"/project/foo.py:1:1:1:10: error: Function is missing a type annotation  [no-untyped-def]
+/project/foo.py:1:1:1:10: note: Use \"-> None\" if function does not return a value
+/project/foo.py:3:5:3:10: error: Incompatible types in assignment  [assignment]
+";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].code,
+        Some(NumberOrString::String("assignment".to_string()))
+    );
+    assert_eq!(diagnostics[0].related_information, None);
+}
+
+#[test]
+fn test_parse_diagnostics_multiple_targets() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let targets = [
+        RelPathBuf::from_filename(root_dir, "foo.py").unwrap(),
+        RelPathBuf::from_filename(root_dir, "bar.py").unwrap(),
+        RelPathBuf::from_filename(root_dir, "clean.py").unwrap(),
+    ];
+    let output = b"/project/bar.py:1:1:1:2: error: Name \"x\" is not defined  [name-defined]
+/project/other.py:2:1:2:2: error: Name \"y\" is not defined  [name-defined]
+/project/foo.py:3:5:3:10: error: Incompatible types in assignment  [assignment]
+/project/foo.py:4:5:4:10: error: Incompatible types in assignment  [assignment]
+";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output).unwrap();
+    let counts: Vec<usize> = diagnostics.iter().map(Vec::len).collect();
+    assert_eq!(counts, [2, 1, 0]);
+    assert_eq!(diagnostics[1][0].message, "Name \"x\" is not defined");
+}
+
+#[test]
+fn test_mypy_ls_diagnostic_set_of() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output = b"/project/foo.py:3:5:3:10: error: Incompatible types in assignment  [assignment]
+/project/foo.py:4:5:4:10: error: Incompatible types in assignment  [assignment]
+";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    let reversed: Vec<Diagnostic> = diagnostics.iter().rev().cloned().collect();
+    assert!(MypyLsDiagnostic::set_of(&diagnostics) == MypyLsDiagnostic::set_of(&reversed));
+    let mut recoded = diagnostics.clone();
+    recoded[0].code = Some(NumberOrString::String("misc".to_string()));
+    assert!(MypyLsDiagnostic::set_of(&diagnostics) != MypyLsDiagnostic::set_of(&recoded));
+    let mut downgraded = diagnostics.clone();
+    downgraded[0].severity = Some(DiagnosticSeverity::WARNING);
+    assert!(MypyLsDiagnostic::set_of(&diagnostics) != MypyLsDiagnostic::set_of(&downgraded));
+}
+
+#[test]
+fn test_parse_diagnostics_deduplication() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let targets = [RelPathBuf::from_filename(root_dir, "foo.py").unwrap()];
+    // The same message at the same span, but with different codes.
+    let output = b"/project/foo.py:3:5:3:10: error: Bad value  [assignment]
+/project/foo.py:3:5:3:10: error: Bad value  [arg-type]
+";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 2);
+    // The same diagnostic, differing only by trailing whitespace.
+    let output = b"/project/foo.py:3:5:3:10: error: Bad value\n\
+                   /project/foo.py:3:5:3:10: error: Bad value   \n";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 1);
+}
+
+#[test]
+fn test_parse_diagnostics_related_notes() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output = b"/project/foo.py:1:1:1:5: note: Standalone note
+/project/foo.py:3:5:3:10: error: No overload variant matches  [call-overload]
+/project/foo.py:3:5:3:10: note: Possible overload variants:
+/project/bar.py:7:1:7:2: error: Something else  [misc]
+/project/bar.py:7:1:7:2: note: Unrelated note
+";
+    let mut diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    diagnostics.sort_by_key(|d| d.range.start.line);
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].message, "Standalone note");
+    assert!(diagnostics[0].related_information.is_none());
+    let related = diagnostics[1].related_information.as_ref().unwrap();
+    assert_eq!(related.len(), 1);
+    assert_eq!(related[0].message, "Possible overload variants:");
+    assert_eq!(related[0].location.uri.path(), "/project/foo.py");
+}
+
+#[test]
+fn test_parse_diagnostics_reveal_type_as_hint() {
+    let root_dir = Path::new("/project");
+    let targets = [RelPathBuf::from_filename(root_dir, "foo.py").unwrap()];
+    let output = b"/project/foo.py:2:13:2:26: note: Revealed type is \"builtins.int\"
+/project/foo.py:3:5:3:10: error: Incompatible types in assignment  [assignment]
+";
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 2);
+    let config = crate::config::parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "reveal_type_as_hint": true }"#,
+    )
+    .unwrap();
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &targets, output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Incompatible types in assignment");
+    let revealed = parse_revealed_types(&config, root_dir, output);
+    assert_eq!(
+        revealed,
+        [(
+            targets[0].clone(),
+            Range::new(Position::new(1, 12), Position::new(1, 25)),
+            "builtins.int".to_string()
+        )]
+    );
+}
+
+#[test]
+fn test_is_output_noise() {
+    assert!(is_output_noise(""));
+    assert!(is_output_noise("   "));
+    assert!(is_output_noise("Success: no issues found in 1 source file"));
+    assert!(is_output_noise(
+        "Found 2 errors in 1 file (checked 3 source files)"
+    ));
+    assert!(!is_output_noise(
+        "foo.py:3: error: Name \"x\" is not defined"
+    ));
+    assert!(!is_output_noise("Daemon crashed!"));
+}
+
+#[test]
+fn test_parse_diagnostics_by_file() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let output = b"/project/foo.py:3:5:3:10: error: Bad foo  [misc]
+/project/bar.py:7:1:7:2: error: Bad bar  [misc]
+/project/foo.py:4:5:4:10: error: Worse foo  [misc]
+";
+    let files = parse_diagnostics_by_file("test", &config, root_dir, output).unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(
+        files[0].0,
+        RelPathBuf::from_filename(root_dir, "foo.py").unwrap()
+    );
+    assert_eq!(files[0].1.len(), 2);
+    assert_eq!(
+        files[1].0,
+        RelPathBuf::from_filename(root_dir, "bar.py").unwrap()
+    );
+    assert_eq!(files[1].1.len(), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_status_reports_running() {
+    let stub = |script: &str| {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    };
+    let runner = &crate::runner::ProcessRunner;
+    assert!(status_reports_running(runner, stub("echo 'Daemon is up and running'")).await);
+    assert!(status_reports_running(runner, stub("echo '  Der Daemon läuft'")).await);
+    assert!(!status_reports_running(runner, stub("echo 'No status file found' && exit 2")).await);
+    assert!(
+        !status_reports_running(runner, stub("echo 'Daemon is up and running' && exit 1")).await
+    );
+    assert!(!status_reports_running(runner, Command::new("/nonexistent/dmypy")).await);
+}
+
+/// An empty directory for the test `name` under the system temp directory, removing whatever a
+/// previous run left there.
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dmypyls-test-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A backend for the project at `/project` whose commands are answered by `runner`.
+#[cfg(test)]
+fn test_backend(runner: crate::runner::MockRunner) -> tower_lsp::LspService<Backend> {
+    test_backend_with_config("{}", runner)
+}
+
+/// Like `test_backend`, with additional configuration given as JSON.
+#[cfg(test)]
+fn test_backend_with_config(
+    config: &str,
+    runner: impl CommandRunner + 'static,
+) -> tower_lsp::LspService<Backend> {
+    let mut settings: Value = serde_json::from_str(config).unwrap();
+    settings["dmypy_command"] = serde_json::json!(["dmypy"]);
+    settings["status_file"] = serde_json::json!("/state/dmypy.json");
+    let config = crate::config::parse_config(&settings.to_string()).unwrap();
+    let base_dirs = xdg::BaseDirectories::with_prefix("dmypyls-test").unwrap();
+    let (service, _) =
+        LspService::new(|client| Backend::new(client, base_dirs, config, Box::new(runner)));
+    *service.inner().root_dir.write().unwrap() = PathBuf::from("/project");
+    service
+}
+
+#[tokio::test]
+async fn test_max_concurrent_checks() {
+    use std::sync::atomic::AtomicUsize;
+    /// Takes a while to run each command, counting how many run at once.
+    #[derive(Default)]
+    struct SlowRunner {
+        running: AtomicUsize,
+        most_running: Arc<AtomicUsize>,
+    }
+    #[tower_lsp::async_trait]
+    impl CommandRunner for SlowRunner {
+        async fn run(&self, _: &mut Command) -> std::io::Result<std::process::Output> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(std::process::Output {
+                status: Default::default(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+    let runner = SlowRunner::default();
+    let most_running = runner.most_running.clone();
+    let config = crate::config::parse_config(
+        r#"{ "dmypy_command": ["dmypy"], "max_concurrent_checks": 2 }"#,
+    )
+    .unwrap();
+    let base_dirs = xdg::BaseDirectories::with_prefix("dmypyls-test").unwrap();
+    let (service, _) =
+        LspService::new(|client| Backend::new(client, base_dirs, config, Box::new(runner)));
+    let backend = service.inner();
+    let check = |name: &str| {
+        let file_path = RelPathBuf::from_filename(Path::new("/project"), name).unwrap();
+        async move {
+            backend
+                .check_files_output("test", &[file_path])
+                .await
+                .unwrap()
+        }
+    };
+    tokio::join!(
+        check("a.py"),
+        check("b.py"),
+        check("c.py"),
+        check("d.py"),
+        check("e.py"),
+        check("f.py"),
+    );
+    assert_eq!(most_running.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_working_dir() {
+    let runner = || crate::runner::MockRunner::new(|_| (0, String::new()));
+    let file_paths = [RelPathBuf::from_filename(Path::new("/project"), "pkg/foo.py").unwrap()];
+    let service = test_backend(runner());
+    let backend = service.inner();
+    let cmd = backend.dmypy_command().unwrap();
+    assert_eq!(cmd.as_std().get_current_dir(), Some(Path::new("/project")));
+    assert_eq!(backend.file_args(&file_paths), ["pkg/foo.py"]);
+    let service = test_backend_with_config(r#"{ "working_dir": "src" }"#, runner());
+    let backend = service.inner();
+    let cmd = backend.dmypy_command().unwrap();
+    assert_eq!(
+        cmd.as_std().get_current_dir(),
+        Some(Path::new("/project/src"))
+    );
+    assert_eq!(backend.file_args(&file_paths), ["/project/pkg/foo.py"]);
+    let service = test_backend_with_config(
+        r#"{ "working_dir": "src", "mypy_config": "mypy.ini", "python_executable": ".venv/bin/python" }"#,
+        runner(),
+    );
+    let options = service.inner().mypy_options("test");
+    let option = |flag: &str| {
+        let index = options.iter().position(|option| option == flag).unwrap();
+        options[index + 1].clone()
+    };
+    assert_eq!(option("--config-file"), "/project/mypy.ini");
+    assert_eq!(option("--python-executable"), "/project/.venv/bin/python");
+    let service = test_backend_with_config(
+        r#"{ "working_dir": "/elsewhere", "mode": "one_shot" }"#,
+        runner(),
+    );
+    let cmd = service.inner().check_command().unwrap();
+    assert_eq!(
+        cmd.as_std().get_current_dir(),
+        Some(Path::new("/elsewhere"))
+    );
+}
+
+#[tokio::test]
+async fn test_dmypy_is_running_with_mock_runner() {
+    let service = test_backend(crate::runner::MockRunner::new(|_| (0, String::new())));
+    assert!(service.inner().dmypy_is_running().await.unwrap());
+    let service = test_backend(crate::runner::MockRunner::new(|_| {
+        (2, "No status file found\n".to_string())
+    }));
+    assert!(!service.inner().dmypy_is_running().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_check_file_with_mock_runner() {
+    let runner =
+        crate::runner::MockRunner::new(|args| match args.iter().any(|arg| arg == "check") {
+            true => (
+                1,
+                "/project/foo.py:2:5:2:9: error: Name \"y\" is not defined  [name-defined]\n\
+                 /project/bar.py:1:1:1:2: error: Name \"z\" is not defined  [name-defined]\n"
+                    .to_string(),
+            ),
+            false => (2, String::new()),
+        });
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend.versions.lock().unwrap().insert(uri.clone(), 3);
+    backend.check_file("test", uri.clone(), 3).await.unwrap();
+
+    let published = backend.published.lock().unwrap();
+    let diagnostics: Vec<&Diagnostic> = published[&uri].iter().map(|d| &d.0).collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Name \"y\" is not defined");
+    assert_eq!(diagnostics[0].range.start, Position::new(1, 4));
+    assert_eq!(published.len(), 1);
+    assert_eq!(
+        calls.lock().unwrap().last().unwrap(),
+        &["--status-file", "/state/dmypy.json", "check", "foo.py"]
+    );
+}
+
+#[tokio::test]
+async fn test_check_file_restarts_idle_daemon() {
+    let runner = crate::runner::MockRunner::new(|args| match args.last().map(String::as_str) {
+        Some("status") => (2, "No status file found\n".to_string()),
+        _ => (0, String::new()),
+    });
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    backend.stopped_idle.store(true, Ordering::SeqCst);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend.check_file("test", uri.clone(), 1).await.unwrap();
+    let subcommands: Vec<String> = calls
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|args| args[2].clone())
+        .collect();
+    assert_eq!(subcommands, ["status", "run", "check"]);
+    assert!(!backend.stopped_idle.load(Ordering::SeqCst));
+    backend.check_file("test", uri, 2).await.unwrap();
+    assert_eq!(calls.lock().unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn test_failed_idle_restart_is_retried() {
+    let runner = crate::runner::MockRunner::new(|args| match args[2].as_str() {
+        "status" => (2, "No status file found\n".to_string()),
+        "run" => (1, "Daemon failed to start\n".to_string()),
+        _ => (0, String::new()),
+    });
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.stopped_idle.store(true, Ordering::SeqCst);
+    let error = backend.ensure_dmypy_running("test").await.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::DaemonNotRunning);
+    backend.touch("test").await;
+    // The next check tries to start the daemon again.
+    assert!(backend.stopped_idle.load(Ordering::SeqCst));
+
+    let service = test_backend_with_config("{}", crate::runner::FailingRunner);
+    let backend = service.inner();
+    backend.stopped_idle.store(true, Ordering::SeqCst);
+    let error = backend.ensure_dmypy_running("test").await.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Io);
+    backend.touch("test").await;
+    assert!(backend.stopped_idle.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_diagnostic_pull() {
+    use tower_lsp::LanguageServer;
+    let runner =
+        crate::runner::MockRunner::new(|args| match args.iter().any(|arg| arg == "check") {
+            true => (
+                1,
+                "/project/foo.py:2:5:2:9: error: Name \"y\" is not defined  [name-defined]\n"
+                    .to_string(),
+            ),
+            false => (0, String::new()),
+        });
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend.versions.lock().unwrap().insert(uri.clone(), 4);
+    let params = |previous_result_id: Option<&str>| DocumentDiagnosticParams {
+        text_document: TextDocumentIdentifier { uri: uri.clone() },
+        identifier: None,
+        previous_result_id: previous_result_id.map(String::from),
+        work_done_progress_params: WorkDoneProgressParams::default(),
+        partial_result_params: PartialResultParams::default(),
+    };
+    let DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(report)) =
+        backend.diagnostic(params(None)).await.unwrap()
+    else {
+        panic!("expected a full report");
+    };
+    let report = report.full_document_diagnostic_report;
+    assert_eq!(report.result_id.as_deref(), Some("4"));
+    assert_eq!(report.items.len(), 1);
+    assert_eq!(report.items[0].message, "Name \"y\" is not defined");
+    let checks = calls.lock().unwrap().len();
+    assert!(matches!(
+        backend.diagnostic(params(Some("4"))).await.unwrap(),
+        DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Unchanged(_))
+    ));
+    assert_eq!(calls.lock().unwrap().len(), checks);
+}
+
+#[tokio::test]
+async fn test_start_check_cancels_previous() {
+    let service = test_backend(crate::runner::MockRunner::new(|_| (0, String::new())));
+    let backend = service.inner();
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    let other = Url::parse("file:///project/bar.py").unwrap();
+    let first = backend.start_check(&uri);
+    let unrelated = backend.start_check(&other);
+    let second = backend.start_check(&uri);
+    assert!(first.is_cancelled());
+    assert!(!second.is_cancelled());
+    assert!(!unrelated.is_cancelled());
+}
+
+#[tokio::test]
+async fn test_check_workspace_passes_root_dir() {
+    let runner = crate::runner::MockRunner::new(|_| (0, String::new()));
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    service.inner().check_workspace("test").await.unwrap();
+    assert_eq!(
+        calls.lock().unwrap().last().unwrap(),
+        &["--status-file", "/state/dmypy.json", "check", "/project"]
+    );
+}
+
+#[tokio::test]
+async fn test_check_workspace_finishes_and_filters_diagnostics() {
+    let runner = crate::runner::MockRunner::new(|_| {
+        (
+            1,
+            "/project/foo.py:1:11:1:11: error: Name \"y\" is not defined  [name-defined]\n\
+             /project/build/gen.py:1:1:1:2: error: Name \"z\" is not defined  [name-defined]\n\
+             /project/closed.py:3:5:3:5: error: Name \"v\" is not defined  [name-defined]\n"
+                .to_string(),
+        )
+    });
+    let service = test_backend_with_config(r#"{ "exclude": ["^build/"] }"#, runner);
+    let backend = service.inner();
+    let foo = Url::parse("file:///project/foo.py").unwrap();
+    let generated = Url::parse("file:///project/build/gen.py").unwrap();
+    for (uri, text) in [(&foo, "x = \"😀\" + y\n"), (&generated, "w = z\n")] {
+        backend.versions.lock().unwrap().insert(uri.clone(), 1);
+        backend
+            .documents
+            .lock()
+            .unwrap()
+            .insert(uri.clone(), text.to_string());
+    }
+    backend.check_workspace("test").await.unwrap();
+    let published = backend.published.lock().unwrap();
+    let diagnostics: Vec<&Diagnostic> = published[&foo].iter().map(|d| &d.0).collect();
+    assert_eq!(diagnostics.len(), 1);
+    // The emoji takes two UTF-16 code units, and the zero-width range is widened.
+    assert_eq!(
+        diagnostics[0].range,
+        Range::new(Position::new(0, 11), Position::new(0, 12))
+    );
+    assert!(published[&generated].is_empty());
+    // Zero-width ranges are widened in files that aren't open, too.
+    let closed = Url::parse("file:///project/closed.py").unwrap();
+    let diagnostics: Vec<&Diagnostic> = published[&closed].iter().map(|d| &d.0).collect();
+    assert_eq!(
+        diagnostics[0].range,
+        Range::new(Position::new(2, 4), Position::new(2, 5))
+    );
+}
+
+#[tokio::test]
+async fn test_check_workspace_surfaces_failure() {
+    let runner = crate::runner::MockRunner::new(|_| (2, "mypy.ini: invalid section\n".to_string()));
+    let service = test_backend(runner);
+    let backend = service.inner();
+    let foo = Url::parse("file:///project/foo.py").unwrap();
+    backend.versions.lock().unwrap().insert(foo.clone(), 1);
+    backend.check_workspace("test").await.unwrap();
+    let published = backend.published.lock().unwrap();
+    let messages: Vec<&str> = published[&foo]
+        .iter()
+        .map(|d| d.0.message.as_str())
+        .collect();
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].starts_with("dmypy exited with"));
+}
+
+#[tokio::test]
+async fn test_check_file_retries_transient_failures() {
+    let attempts = Arc::new(Mutex::new(0));
+    let runner = crate::runner::MockRunner::new({
+        let attempts = attempts.clone();
+        move |_| {
+            let mut attempts = attempts.lock().unwrap();
+            *attempts += 1;
+            match *attempts {
+                1 => (2, "Daemon is busy with another request\n".to_string()),
+                _ => (
+                    1,
+                    "/project/foo.py:2:5:2:9: error: Name \"y\" is not defined  [name-defined]\n"
+                        .to_string(),
+                ),
+            }
+        }
+    });
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend.check_file("test", uri.clone(), 1).await.unwrap();
+    assert_eq!(*attempts.lock().unwrap(), 2);
+    assert_eq!(backend.published.lock().unwrap()[&uri].len(), 1);
+
+    let runner = crate::runner::MockRunner::new(|_| (2, "Daemon is busy\n".to_string()));
+    let calls = runner.calls.clone();
+    let service = test_backend_with_config(r#"{ "max_retries": 1 }"#, runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    backend.check_file("test", uri, 1).await.unwrap();
+    assert_eq!(calls.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_did_save_recheck_dependents() {
+    use tower_lsp::LanguageServer;
+    let runner = crate::runner::MockRunner::new(|args| match args.last().map(String::as_str) {
+        Some("/project") => (
+            1,
+            "/project/bar.py:1:1:1:2: error: Name \"z\" is not defined  [name-defined]\n"
+                .to_string(),
+        ),
+        _ => (0, String::new()),
+    });
+    let service = test_backend_with_config(
+        r#"{ "recheck_dependents": true, "debounce_ms": 0 }"#,
+        runner,
+    );
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let saved = Url::parse("file:///project/foo.py").unwrap();
+    let dependent = Url::parse("file:///project/bar.py").unwrap();
+    backend.versions.lock().unwrap().insert(saved.clone(), 1);
+    backend
+        .versions
+        .lock()
+        .unwrap()
+        .insert(dependent.clone(), 1);
+    backend
+        .did_save(DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: saved.clone() },
+            text: None,
+        })
+        .await;
+    let published = backend.published.lock().unwrap();
+    assert_eq!(published[&dependent].len(), 1);
+    assert!(published[&saved].is_empty());
+}
+
+#[tokio::test]
+async fn test_wait_for_checks() {
+    let service = test_backend(crate::runner::MockRunner::new(|_| (0, String::new())));
+    let backend = service.inner();
+    backend.wait_for_checks().await;
+    let check = InFlightCheck::new(backend);
+    let finished = AtomicBool::new(false);
+    tokio::join!(
+        async {
+            backend.wait_for_checks().await;
+            assert!(finished.load(Ordering::SeqCst));
+        },
+        async {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            finished.store(true, Ordering::SeqCst);
+            drop(check);
+        }
+    );
+    assert_eq!(backend.checks_in_flight.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_check_file_disabled_by_directive() {
+    let runner = crate::runner::MockRunner::new(|_| (0, String::new()));
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend
+        .documents
+        .lock()
+        .unwrap()
+        .insert(uri.clone(), "# dmypyls: disable\nx: int = ''\n".to_string());
+    backend.check_file("test", uri.clone(), 1).await.unwrap();
+    assert!(calls.lock().unwrap().is_empty());
+    assert!(backend.published.lock().unwrap()[&uri].is_empty());
+}
+
+#[tokio::test]
+async fn test_check_file_shebang_script() {
+    let runner = crate::runner::MockRunner::new(|_| (0, String::new()));
+    let calls = runner.calls.clone();
+    let service = test_backend_with_config(r#"{ "check_shebang_scripts": true }"#, runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let script = Url::parse("file:///project/bin/tool").unwrap();
+    let shell = Url::parse("file:///project/bin/run").unwrap();
+    {
+        let mut documents = backend.documents.lock().unwrap();
+        documents.insert(
+            script.clone(),
+            "#!/usr/bin/env python3\nx = 1\n".to_string(),
+        );
+        documents.insert(shell.clone(), "#!/bin/sh\necho hi\n".to_string());
+    }
+    backend.check_file("test", shell.clone(), 1).await.unwrap();
+    assert!(calls.lock().unwrap().is_empty());
+    backend.check_file("test", script.clone(), 1).await.unwrap();
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].last().unwrap(), "bin/tool");
+}
+
+#[tokio::test]
+async fn test_start_dmypy_extra_run_args() {
+    let runner = crate::runner::MockRunner::new(|_| (0, String::new()));
+    let calls = runner.calls.clone();
+    let service = test_backend_with_config(
+        r#"{ "extra_run_args": ["--cache-fine-grained", "--python-version", "3.11"] }"#,
+        runner,
+    );
+    service.inner().start_dmypy("test").await.unwrap();
+    let calls = calls.lock().unwrap();
+    let args = &calls[0];
+    assert_eq!(
+        args[..4],
+        ["--status-file", "/state/dmypy.json", "run", "--"]
+    );
+    assert_eq!(
+        args[args.len() - 4..],
+        [
+            "--cache-fine-grained",
+            "--python-version",
+            "3.11",
+            "/project"
+        ]
+    );
+    assert!(args.iter().any(|arg| arg == "--show-column-numbers"));
+}
+
+#[tokio::test]
+async fn test_check_file_surfaces_failures() {
+    let service = test_backend(crate::runner::MockRunner::new(|_| {
+        (
+            2,
+            "mypy.ini: [mypy]: Unrecognized option: strictest = True\n".to_string(),
+        )
+    }));
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend.check_file("test", uri.clone(), 1).await.unwrap();
+    let published = backend.published.lock().unwrap();
+    let diagnostics: Vec<&Diagnostic> = published[&uri].iter().map(|d| &d.0).collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].range.start, Position::new(0, 0));
+    assert!(diagnostics[0].message.contains("Unrecognized option"));
+}
+
+#[tokio::test]
+async fn test_check_file_ignored_errors_are_not_failures() {
+    let service = test_backend_with_config(
+        r#"{ "ignore_codes": ["name-defined"], "surface_daemon_errors": true }"#,
+        crate::runner::MockRunner::new(|_| {
+            (
+                1,
+                "/project/foo.py:2:1:2:5: error: Name \"x\" is not defined  [name-defined]\n"
+                    .to_string(),
+            )
+        }),
+    );
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend.check_file("test", uri.clone(), 1).await.unwrap();
+    assert!(backend
+        .published
+        .lock()
+        .unwrap()
+        .get(&uri)
+        .is_none_or(HashSet::is_empty));
+}
+
+#[tokio::test]
+async fn test_check_file_outside_root() {
+    let respond = |args: &[String]| match args.last().map(String::as_str) {
+        Some("helpers.py") => (
+            1,
+            "/shared/helpers.py:2:1:2:5: error: Name \"x\" is not defined  [name-defined]\n"
+                .to_string(),
+        ),
+        _ => (0, String::new()),
+    };
+    let uri = Url::parse("file:///shared/helpers.py").unwrap();
+    let runner = crate::runner::MockRunner::new(respond);
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    backend.check_file("test", uri.clone(), 1).await.unwrap();
+    assert!(backend.published.lock().unwrap().is_empty());
+    assert!(calls.lock().unwrap().is_empty());
+
+    let runner = crate::runner::MockRunner::new(respond);
+    let calls = runner.calls.clone();
+    let service = test_backend_with_config(
+        r#"{ "check_files_outside_root": true, "mypy_config": "/project/mypy.ini", "exclude": ["_pb2\\.py$"] }"#,
+        runner,
+    );
+    let backend = service.inner();
+    backend.ready.store(true, Ordering::SeqCst);
+    backend.check_file("test", uri.clone(), 1).await.unwrap();
+    // The project's mypy configuration isn't used for files outside it.
+    assert!(!calls.lock().unwrap()[0].contains(&"--config-file".to_string()));
+    let excluded = Url::parse("file:///shared/helpers_pb2.py").unwrap();
+    let disabled = Url::parse("file:///shared/scratch.py").unwrap();
+    backend
+        .documents
+        .lock()
+        .unwrap()
+        .insert(disabled.clone(), "# dmypyls: disable\nx\n".to_string());
+    backend
+        .check_file("test", excluded.clone(), 1)
+        .await
+        .unwrap();
+    backend
+        .check_file("test", disabled.clone(), 1)
+        .await
+        .unwrap();
+    assert_eq!(calls.lock().unwrap().len(), 1);
+    let published = backend.published.lock().unwrap();
+    let diagnostics: Vec<&Diagnostic> = published[&uri].iter().map(|d| &d.0).collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].range.start, Position::new(1, 0));
+    assert!(published[&excluded].is_empty());
+    assert!(published[&disabled].is_empty());
+}
+
+#[tokio::test]
+async fn test_published_diagnostics_json() {
+    let service = test_backend(crate::runner::MockRunner::new(|_| (0, String::new())));
+    let backend = service.inner();
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    let diagnostic = |line| Diagnostic {
+        range: Range::new(Position::new(line, 0), Position::new(line, 1)),
+        message: format!("error on line {line}"),
+        ..Default::default()
+    };
+    backend.published.lock().unwrap().insert(
+        uri,
+        MypyLsDiagnostic::set_of(&[diagnostic(3), diagnostic(1)]),
+    );
+    let json = backend.published_diagnostics_json();
+    let messages: Vec<&str> = json["file:///project/foo.py"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|diagnostic| diagnostic["message"].as_str().unwrap())
+        .collect();
+    assert_eq!(messages, ["error on line 1", "error on line 3"]);
+}
+
+#[tokio::test]
+async fn test_self_test() {
+    let runner = crate::runner::MockRunner::new(|args| match args.last().map(String::as_str) {
+        Some("--version") => (0, "dmypy 1.13.0 (compiled: yes)\n".to_string()),
+        _ => (0, "Daemon is up and running\n".to_string()),
+    });
+    let service = test_backend(runner);
+    let report = service.inner().self_test().await;
+    assert!(report.contains("dmypy command: dmypy\n"));
+    assert!(report.contains("dmypy --version: dmypy 1.13.0 (compiled: yes)\n"));
+    assert!(report.contains("dmypy status: Daemon is up and running\n"));
+    assert!(report.contains("status file /state/dmypy.json writable: no"));
+}
+
+#[tokio::test]
+async fn test_daemon_stats() {
+    let runner = crate::runner::MockRunner::new(|_| {
+        (
+            0,
+            "graph_size              :        412\n\
+             memory_maxrss_mib       :    301.250\n\
+             memory_rss_mib          :    250.500\n\
+             pid                     :       4242\n\
+             Daemon is up and running\n"
+                .to_string(),
+        )
+    });
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    let summary = service.inner().daemon_stats().await.unwrap();
+    assert_eq!(
+        summary,
+        "dmypy memory: 250.500 MiB (peak 301.250 MiB)\n\
+         modules: 412\n\
+         uptime: unknown (the daemon was not started by this session)\n\
+         pid: 4242"
+    );
+    assert_eq!(calls.lock().unwrap()[0][2..], ["status", "--verbose"]);
+}
+
+#[tokio::test]
+async fn test_daemon_stats_without_verbose() {
+    let runner = crate::runner::MockRunner::new(|args| match args.last().map(String::as_str) {
+        Some("--verbose") => (2, String::new()),
+        _ => (0, "Daemon is up and running\n".to_string()),
+    });
+    let calls = runner.calls.clone();
+    let service = test_backend(runner);
+    let backend = service.inner();
+    *backend.daemon_started.lock().unwrap() = Some(std::time::Instant::now());
+    let summary = backend.daemon_stats().await.unwrap();
+    assert_eq!(
+        summary,
+        "dmypy memory: not reported by this version of dmypy\n\
+         modules: not reported by this version of dmypy\n\
+         uptime: 0s"
+    );
+    assert_eq!(calls.lock().unwrap().len(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_find_executable() {
+    assert_eq!(find_executable("/bin/sh"), Some(PathBuf::from("/bin/sh")));
+    assert!(find_executable("sh").is_some());
+    assert_eq!(find_executable("dmypyls-nonexistent-program"), None);
+    assert_eq!(find_executable("/nonexistent/dmypy"), None);
+}
+
+#[tokio::test]
+async fn test_did_change_watched_files_clears_deleted() {
+    use tower_lsp::LanguageServer;
+    let service = test_backend(crate::runner::MockRunner::new(|_| (0, String::new())));
+    let backend = service.inner();
+    let uri = |path: &str| Url::parse(&format!("file:///project/{path}")).unwrap();
+    let diagnostic = Diagnostic {
+        message: "error".to_string(),
+        ..Default::default()
+    };
+    for path in ["foo.py", "pkg/bar.py", "pkg_other.py"] {
+        backend.versions.lock().unwrap().insert(uri(path), 1);
+        backend
+            .documents
+            .lock()
+            .unwrap()
+            .insert(uri(path), String::new());
+        backend
+            .publish_diagnostics(uri(path), vec![diagnostic.clone()], Some(1))
+            .await;
+    }
+    let deleted = |uri| FileEvent {
+        uri,
+        typ: FileChangeType::DELETED,
+    };
+    backend
+        .did_change_watched_files(DidChangeWatchedFilesParams {
+            changes: vec![deleted(uri("foo.py")), deleted(uri("pkg"))],
+        })
+        .await;
+    let published = backend.published.lock().unwrap();
+    assert!(!published.contains_key(&uri("foo.py")));
+    assert!(!published.contains_key(&uri("pkg/bar.py")));
+    assert!(published.contains_key(&uri("pkg_other.py")));
+    let versions = backend.versions.lock().unwrap();
+    assert_eq!(versions.keys().collect::<Vec<_>>(), [&uri("pkg_other.py")]);
+    assert!(!backend
+        .documents
+        .lock()
+        .unwrap()
+        .contains_key(&uri("foo.py")));
+}
+
+#[tokio::test]
+async fn test_document_highlight() {
+    use tower_lsp::LanguageServer;
+    let dir = test_dir("highlight").canonicalize().unwrap();
+    let file = dir.join("foo.py");
+    let text = "x = 1\ndef f(x):\n    return x\nprint(x)\n";
+    std::fs::write(&file, text).unwrap();
+    let prefix = file.display().to_string();
+    let runner = crate::runner::MockRunner::new(move |args| {
+        let location = args.last().unwrap();
+        // The parameter `x` of `f` is defined on line 2; everything else is the global `x`.
+        match location.starts_with(&format!("{prefix}:2:"))
+            || location.starts_with(&format!("{prefix}:3:"))
+        {
+            true => (0, format!("{prefix}:2:7:x\n")),
+            false => (0, format!("{prefix}:1:1:x\n")),
+        }
+    });
+    let service = test_backend(runner);
+    let backend = service.inner();
+    let uri = Url::from_file_path(&file).unwrap();
+    backend
+        .documents
+        .lock()
+        .unwrap()
+        .insert(uri.clone(), text.to_string());
+    let params = || DocumentHighlightParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            position: Position::new(3, 6),
+        },
+        work_done_progress_params: WorkDoneProgressParams::default(),
+        partial_result_params: PartialResultParams::default(),
+    };
+    let lines = |highlights: Option<Vec<DocumentHighlight>>| {
+        highlights
+            .unwrap()
+            .iter()
+            .map(|highlight| highlight.range.start.line)
+            .collect::<Vec<_>>()
+    };
+    // Without inspect, every textual match is highlighted.
+    assert_eq!(
+        lines(backend.document_highlight(params()).await.unwrap()),
+        [0, 1, 2, 3]
+    );
+    backend.supports_inspect.store(true, Ordering::Relaxed);
+    assert_eq!(
+        lines(backend.document_highlight(params()).await.unwrap()),
+        [0, 3]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_document_highlight_falls_back_when_inspect_fails() {
+    use tower_lsp::LanguageServer;
+    let service = test_backend_with_config("{}", crate::runner::FailingRunner);
+    let backend = service.inner();
+    backend.supports_inspect.store(true, Ordering::Relaxed);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend
+        .documents
+        .lock()
+        .unwrap()
+        .insert(uri.clone(), "x = 1\nprint(x)\n".to_string());
+    let highlights = backend
+        .document_highlight(DocumentHighlightParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri },
+                position: Position::new(1, 6),
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(highlights.len(), 2);
+}
+
+#[tokio::test]
+async fn test_hover_after_non_bmp_character() {
+    use tower_lsp::LanguageServer;
+    let dir = test_dir("hover-non-bmp").canonicalize().unwrap();
+    let file = dir.join("foo.py");
+    let text = "s = \"😀\"; x = 1\n";
+    std::fs::write(&file, text).unwrap();
+    let location = format!("{}:1:10", file.display());
+    let runner = crate::runner::MockRunner::new(move |args| {
+        match args.iter().any(|arg| arg == "--include-span") {
+            // The emoji is one character to dmypy, but two UTF-16 code units to the client.
+            true if *args.last().unwrap() == location => {
+                (0, "1:10:1:11 -> \"builtins.int\"\n".to_string())
+            }
+            _ => (1, String::new()),
+        }
+    });
+    let service = test_backend(runner);
+    let backend = service.inner();
+    backend.supports_inspect.store(true, Ordering::Relaxed);
+    let uri = Url::from_file_path(&file).unwrap();
+    backend
+        .documents
+        .lock()
+        .unwrap()
+        .insert(uri.clone(), text.to_string());
+    let hover = backend
+        .hover(HoverParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri },
+                position: Position::new(0, 10),
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        hover.range,
+        Some(Range::new(Position::new(0, 10), Position::new(0, 11)))
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_initialize_reads_config_from_root() {
+    use tower_lsp::LanguageServer;
+    let dir = test_dir("initialize-config");
+    std::fs::write(dir.join(CONFIG_FILE_NAME), "debounce_ms: 7\n").unwrap();
+    let service = test_backend(crate::runner::MockRunner::new(|_| (0, String::new())));
+    let backend = service.inner();
+    backend
+        .initialize(InitializeParams {
+            root_uri: Some(Url::from_file_path(&dir).unwrap()),
+            ..InitializeParams::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(backend.config().debounce_ms, 7);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_disabled_language_features() {
+    use tower_lsp::LanguageServer;
+    let runner = crate::runner::MockRunner::new(|_| (0, "\"builtins.int\"\n".to_string()));
+    let calls = runner.calls.clone();
+    let service = test_backend_with_config(
+        r#"{ "enable_hover": false, "enable_definition": false, "enable_completion": false }"#,
+        runner,
+    );
+    let backend = service.inner();
+    backend.supports_inspect.store(true, Ordering::Relaxed);
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    backend
+        .documents
+        .lock()
+        .unwrap()
+        .insert(uri.clone(), "x = 1\nx.\n".to_string());
+    let position = |line, character| TextDocumentPositionParams {
+        text_document: TextDocumentIdentifier { uri: uri.clone() },
+        position: Position::new(line, character),
+    };
+    let hover = backend
+        .hover(HoverParams {
+            text_document_position_params: position(0, 0),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        })
+        .await
+        .unwrap();
+    assert!(hover.is_none());
+    let definition = backend
+        .goto_definition(GotoDefinitionParams {
+            text_document_position_params: position(0, 0),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        })
+        .await
+        .unwrap();
+    assert!(definition.is_none());
+    let completion = backend
+        .completion(CompletionParams {
+            text_document_position: position(1, 2),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+            context: None,
+        })
+        .await
+        .unwrap();
+    assert!(completion.is_none());
+    assert!(calls.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_did_change_keeps_last_text() {
+    use tower_lsp::LanguageServer;
+    let service = test_backend(crate::runner::MockRunner::new(|_| (0, String::new())));
+    let backend = service.inner();
+    let uri = Url::parse("file:///project/foo.py").unwrap();
+    let change = |version, texts: &[&str]| DidChangeTextDocumentParams {
+        text_document: VersionedTextDocumentIdentifier {
+            uri: uri.clone(),
+            version,
+        },
+        content_changes: texts
+            .iter()
+            .map(|text| TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_string(),
+            })
+            .collect(),
+    };
+    backend.did_change(change(2, &["x = 1\n", "x = 2\n"])).await;
+    assert_eq!(backend.document_text(&uri).as_deref(), Some("x = 2\n"));
+    backend.did_change(change(3, &[])).await;
+    assert_eq!(backend.document_text(&uri).as_deref(), Some("x = 2\n"));
+    assert_eq!(backend.versions.lock().unwrap()[&uri], 3);
+}
+
+#[test]
+fn test_parse_diagnostics_colored() {
+    let config = crate::config::parse_config("{}").unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output =
+        b"\x1b[1m/project/foo.py:3:5:3:10: \x1b[31merror:\x1b(B\x1b[m Incompatible types in \
+assignment  \x1b[33m[assignment]\x1b(B\x1b[m
+\x1b[1m\x1b[31mFound 1 error in 1 file\x1b(B\x1b[m
+";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Incompatible types in assignment");
+    assert_eq!(
+        diagnostics[0].code,
+        Some(NumberOrString::String("assignment".to_string()))
+    );
+    assert_eq!(diagnostics[0].range.start, Position::new(2, 4));
+}
+
+#[test]
+fn test_summarize_diagnostics() {
+    let config = crate::config::parse_config("{}").unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output = b"/project/foo.py:1:1:1:5: note: Standalone note
+/project/foo.py:2:1:2:5: error: Name \"y\" is not defined  [name-defined]
+/project/foo.py:3:1:3:5: error: Incompatible types in assignment  [assignment]
+/project/foo.py:4:1:4:5: error: Missing return statement  [return]
+";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(summarize_diagnostics(&diagnostics), "3 errors, 1 hint");
+    assert_eq!(summarize_diagnostics(&diagnostics[3..]), "1 error");
+    assert_eq!(summarize_diagnostics(&[]), "no problems");
+}
+
+#[test]
+fn test_limit_diagnostics() {
+    let diagnostic = |line| Diagnostic {
+        range: Range::new(Position::new(line, 0), Position::new(line, 1)),
+        message: format!("error on line {line}"),
+        ..Default::default()
+    };
+    let mut diagnostics = vec![diagnostic(7), diagnostic(2), diagnostic(5), diagnostic(1)];
+    limit_diagnostics(&mut diagnostics, 2, "dmypy");
+    let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "error on line 1",
+            "error on line 2",
+            "2 more diagnostics suppressed"
+        ]
+    );
+    assert_eq!(diagnostics[2].range.start, Position::new(0, 0));
+    assert_eq!(
+        diagnostics[2].severity,
+        Some(DiagnosticSeverity::INFORMATION)
+    );
+    let mut diagnostics = vec![diagnostic(7), diagnostic(2)];
+    limit_diagnostics(&mut diagnostics, 2, "dmypy");
+    assert_eq!(diagnostics.len(), 2);
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_root_dir_from_params() {
+    let mut params = InitializeParams {
+        root_path: Some("/from/path".to_string()),
+        ..InitializeParams::default()
+    };
+    assert_eq!(root_dir_from_params(&params), PathBuf::from("/from/path"));
+    params.root_uri = Some(Url::parse("file:///from/uri").unwrap());
+    assert_eq!(root_dir_from_params(&params), PathBuf::from("/from/uri"));
+    params.root_uri = None;
+    params.root_path = Some("relative/root".to_string());
+    assert_eq!(
+        root_dir_from_params(&params),
+        std::env::current_dir().unwrap().join("relative/root")
+    );
+    params.root_path = None;
+    assert_eq!(
+        root_dir_from_params(&params),
+        std::env::current_dir().unwrap()
+    );
+}
+
+#[test]
+fn test_parse_diagnostics_without_error_end() {
+    let config = crate::config::parse_config(r#"{ "dmypy_command": ["dmypy"] }"#).unwrap();
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    let output = b"/project/foo.py:3:5: error: Name \"x\" is not defined  [name-defined]\n";
+    let diagnostics = parse_diagnostics("test", &config, root_dir, &[target], output)
+        .unwrap()
+        .remove(0);
+    assert_eq!(diagnostics.len(), 1);
+    let start = Position {
+        line: 2,
+        character: 4,
+    };
+    let end = Position {
+        line: 2,
+        character: 5,
+    };
+    assert_eq!(diagnostics[0].range, Range { start, end });
+    assert_eq!(diagnostics[0].message, "Name \"x\" is not defined");
 }
@@ -0,0 +1,189 @@
+use regex::Regex;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// Whether `content` has a line consisting of the given `[section]` header.
+fn has_section(content: &str, section: &str) -> bool {
+    content.lines().any(|line| line.trim() == section)
+}
+
+/// Whether `path` is a mypy configuration file, in the order mypy itself looks for them.
+fn is_mypy_config(path: &Path) -> bool {
+    let Some(file_name) = path.file_name().and_then(|file_name| file_name.to_str()) else {
+        return false;
+    };
+    match file_name {
+        "mypy.ini" | ".mypy.ini" => path.is_file(),
+        "pyproject.toml" => read_to_string(path).is_ok_and(|c| tool_mypy(&c).is_some()),
+        "setup.cfg" => read_to_string(path).is_ok_and(|c| has_section(&c, "[mypy]")),
+        _ => false,
+    }
+}
+
+/// Walks up from `start` looking for `mypy.ini`, `.mypy.ini`, a `pyproject.toml` with a
+/// `[tool.mypy]` section or a `setup.cfg` with a `[mypy]` section.
+pub(crate) fn find_mypy_config(start: &Path) -> Option<PathBuf> {
+    start.ancestors().find_map(|dir| {
+        ["mypy.ini", ".mypy.ini", "pyproject.toml", "setup.cfg"]
+            .into_iter()
+            .map(|file_name| dir.join(file_name))
+            .find(|path| is_mypy_config(path))
+    })
+}
+
+/// The `[tool.mypy]` table of a `pyproject.toml`, if it parses and has one.
+fn tool_mypy(content: &str) -> Option<toml::Table> {
+    let mut pyproject: toml::Table = toml::from_str(content).ok()?;
+    match pyproject.remove("tool")? {
+        toml::Value::Table(mut tool) => match tool.remove("mypy")? {
+            toml::Value::Table(mypy) => Some(mypy),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The lines of the given `[section]`, up to the next section header.
+fn section_lines<'a>(content: &'a str, section: &str) -> Vec<&'a str> {
+    content
+        .lines()
+        .skip_while(|line| line.trim() != section)
+        .skip(1)
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .collect()
+}
+
+/// Reads the `exclude` regexes from a mypy configuration file. In INI files, `exclude` is a
+/// single regex whose value may continue on indented lines; in `pyproject.toml` it is a string or
+/// an array of strings.
+pub(crate) fn exclude_patterns(path: &Path) -> Vec<String> {
+    let Ok(content) = read_to_string(path) else {
+        return Vec::new();
+    };
+    if path
+        .file_name()
+        .is_some_and(|file_name| file_name == "pyproject.toml")
+    {
+        return match tool_mypy(&content).and_then(|mut mypy| mypy.remove("exclude")) {
+            Some(toml::Value::String(pattern)) => vec![pattern],
+            Some(toml::Value::Array(patterns)) => patterns
+                .into_iter()
+                .filter_map(|pattern| match pattern {
+                    toml::Value::String(pattern) => Some(pattern),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+    }
+    let lines = section_lines(&content, "[mypy]");
+    let Some(start) = lines.iter().position(|line| {
+        line.split_once('=')
+            .is_some_and(|(key, _)| key.trim() == "exclude")
+    }) else {
+        return Vec::new();
+    };
+    let value = lines[start].split_once('=').unwrap().1;
+    let continuation = lines[start + 1..]
+        .iter()
+        .take_while(|line| line.starts_with([' ', '\t']) && !line.trim().is_empty());
+    let pattern: String = std::iter::once(value)
+        .chain(continuation.copied())
+        .map(str::trim)
+        .collect();
+    if pattern.is_empty() {
+        Vec::new()
+    } else {
+        vec![pattern]
+    }
+}
+
+/// Whether mypy would exclude the file at `path`, relative to the project root. Like mypy, this
+/// searches for the patterns anywhere in the path, using forward slashes on all platforms.
+pub(crate) fn is_excluded(patterns: &[Regex], path: &Path) -> bool {
+    let path = path.to_string_lossy().replace('\\', "/");
+    patterns.iter().any(|pattern| pattern.is_match(&path))
+}
+
+#[test]
+fn test_find_mypy_config_walks_up() {
+    let dir = crate::test_dir("mypy-config-walk");
+    let project = dir.join("project");
+    std::fs::create_dir_all(project.join("src/pkg")).unwrap();
+    std::fs::write(project.join("setup.cfg"), "[metadata]\nname = x\n").unwrap();
+    assert_eq!(find_mypy_config(&project.join("src/pkg")), None);
+    std::fs::write(
+        project.join("setup.cfg"),
+        "[metadata]\nname = x\n\n[mypy]\nstrict = True\n",
+    )
+    .unwrap();
+    assert_eq!(
+        find_mypy_config(&project.join("src/pkg")),
+        Some(project.join("setup.cfg"))
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_find_mypy_config_prefers_mypy_ini() {
+    let dir = crate::test_dir("mypy-config-order");
+    let project = dir.join("project");
+    std::fs::create_dir_all(project.join("src/pkg")).unwrap();
+    std::fs::write(
+        project.join("pyproject.toml"),
+        "[tool.mypy]\nstrict = true\n",
+    )
+    .unwrap();
+    assert_eq!(
+        find_mypy_config(&project),
+        Some(project.join("pyproject.toml"))
+    );
+    std::fs::write(project.join("mypy.ini"), "[mypy]\n").unwrap();
+    assert_eq!(find_mypy_config(&project), Some(project.join("mypy.ini")));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_is_excluded() {
+    let patterns = [
+        Regex::new(r"^build/").unwrap(),
+        Regex::new(r"_pb2\.py$").unwrap(),
+    ];
+    assert!(is_excluded(&patterns, Path::new("build/lib/mod.py")));
+    assert!(is_excluded(&patterns, Path::new("pkg/api_pb2.py")));
+    assert!(!is_excluded(&patterns, Path::new("pkg/build/mod.py")));
+    assert!(!is_excluded(&patterns, Path::new("pkg/api.py")));
+    assert!(!is_excluded(&[], Path::new("pkg/api.py")));
+}
+
+#[test]
+fn test_exclude_patterns() {
+    let dir = crate::test_dir("mypy-config-exclude");
+    let project = dir.join("project");
+    std::fs::create_dir_all(project.join("src/pkg")).unwrap();
+    let mypy_ini = project.join("mypy.ini");
+    std::fs::write(
+        &mypy_ini,
+        "[mypy]\nexclude = (?x)(\n    ^build/\n    | _pb2\\.py$\n  )\nstrict = True\n",
+    )
+    .unwrap();
+    assert_eq!(
+        exclude_patterns(&mypy_ini),
+        [r"(?x)(^build/| _pb2\.py$)".to_string()]
+    );
+    let pyproject = project.join("pyproject.toml");
+    std::fs::write(
+        &pyproject,
+        "[tool.black]\nexclude = \"ignored\"\n\n[tool.mypy]\nexclude = [\n  \"^build/\",  # generated\n  '_pb2\\.py$',\n]\n",
+    )
+    .unwrap();
+    assert_eq!(
+        exclude_patterns(&pyproject),
+        ["^build/".to_string(), r"_pb2\.py$".to_string()]
+    );
+    std::fs::write(&pyproject, "[tool.mypy]\nexclude = \"^vendor/\"\n").unwrap();
+    assert_eq!(exclude_patterns(&pyproject), ["^vendor/".to_string()]);
+    std::fs::write(&pyproject, "[tool]\nmypy.exclude = \"_pb2\\\\.py$\"\n").unwrap();
+    assert_eq!(exclude_patterns(&pyproject), [r"_pb2\.py$".to_string()]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,9 +1,9 @@
 use crate::error::{Error, Result};
 use std::ops::Deref;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use tower_lsp::lsp_types::Url;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub(crate) struct RelPathBuf {
     root_dir: PathBuf,
     path_buf: PathBuf,
@@ -15,18 +15,44 @@ impl std::fmt::Display for RelPathBuf {
     }
 }
 
+/// Two paths are equal if they refer to the same file once `.` and `..` components (and trailing
+/// slashes) are normalized away, e.g. `./foo.py` and `foo.py`.
+impl PartialEq for RelPathBuf {
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized_path() == other.normalized_path()
+    }
+}
+
+impl Eq for RelPathBuf {}
+
 impl RelPathBuf {
     pub(crate) fn from_uri(root_dir: PathBuf, uri: Url) -> Result<Self> {
         // Compute the relative path from root_dir to uri assuming uri is a file path.
-        let path_buf = uri
-            .to_file_path()
-            .map_err(|_| "uri is not a file path")?
-            .strip_prefix(&root_dir)
-            .map_err(|e| Error::from(format!("uri is not a child of root_dir [{e}]")))?
-            .to_path_buf();
+        let path_buf = uri_to_path(&uri).and_then(|path| {
+            strip_prefix(&path, &root_dir).ok_or_else(|| {
+                Error::from(format!(
+                    "uri is not a child of root_dir [{} is not in {}]",
+                    path.display(),
+                    root_dir.display()
+                ))
+            })
+        })?;
         Ok(Self { root_dir, path_buf })
     }
 
+    /// A path relative to the directory containing the file at `uri`, for files that are outside
+    /// the project root.
+    pub(crate) fn from_uri_in_parent(uri: Url) -> Result<Self> {
+        let path = uri_to_path(&uri)?;
+        let (Some(root_dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(format!("{uri} has no parent directory").into());
+        };
+        Ok(Self {
+            root_dir: root_dir.to_path_buf(),
+            path_buf: PathBuf::from(file_name),
+        })
+    }
+
     pub(crate) fn from_filename(root_dir: &Path, filename: &str) -> Result<Self> {
         let path_buf = PathBuf::from(filename);
         if path_buf.is_relative() {
@@ -35,16 +61,143 @@ impl RelPathBuf {
                 path_buf,
             })
         } else {
-            let path_buf = path_buf
-                .strip_prefix(root_dir)
-                .map_err(|e| Error::from(format!("filename prefix could not be stripped [{e}]")))?
-                .to_path_buf();
+            let path_buf = strip_root(root_dir, &path_buf).ok_or_else(|| {
+                Error::from(format!(
+                    "filename prefix could not be stripped [{filename} is not in {}]",
+                    root_dir.display()
+                ))
+            })?;
             Ok(Self {
                 root_dir: root_dir.to_path_buf(),
                 path_buf,
             })
         }
     }
+
+    pub(crate) fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    pub(crate) fn absolute_path(&self) -> PathBuf {
+        self.root_dir.join(&self.path_buf)
+    }
+
+    /// The absolute path with `.` and `..` components resolved lexically, without touching the
+    /// file system.
+    fn normalized_path(&self) -> PathBuf {
+        let mut normalized = PathBuf::new();
+        for component in self.absolute_path().components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                component => normalized.push(component),
+            }
+        }
+        normalized
+    }
+
+    /// The dotted name of the module at this path, relative to the longest of `source_roots` that
+    /// contains it (or else the project root). Absolute source roots must be under the project root.
+    pub(crate) fn to_module(&self, source_roots: &[PathBuf]) -> Option<String> {
+        let source_root = source_roots
+            .iter()
+            .filter_map(|source_root| match source_root.is_absolute() {
+                true => source_root.strip_prefix(&self.root_dir).ok(),
+                false => Some(source_root.as_path()),
+            })
+            .filter(|source_root| self.path_buf.starts_with(source_root))
+            .max_by_key(|source_root| source_root.components().count())
+            .unwrap_or(Path::new(""));
+        crate::suggest::module_name(self.path_buf.strip_prefix(source_root).ok()?)
+    }
+
+    pub(crate) fn to_uri(&self) -> Result<Url> {
+        Url::from_file_path(self.absolute_path())
+            .map_err(|_| Error::from(format!("{self} cannot be converted to a uri")))
+    }
+}
+
+/// Converts a `file` URI to a path. `Url::to_file_path` rejects URIs with a host other than
+/// `localhost`, which some editors send, so in that case the path is percent-decoded directly.
+fn uri_to_path(uri: &Url) -> Result<PathBuf> {
+    if uri.scheme() != "file" {
+        log::warn!("[uri_to_path] rejecting uri with a non-file scheme: {uri}");
+        return Err(format!("{uri} is not a file uri").into());
+    }
+    if let Ok(path) = uri.to_file_path() {
+        return Ok(path);
+    }
+    log::info!("[uri_to_path] decoding the path of {uri} directly");
+    let path = percent_decode(uri.path()).ok_or_else(|| {
+        log::warn!("[uri_to_path] invalid percent escapes in {uri}");
+        Error::from(format!("{uri} has invalid percent escapes"))
+    })?;
+    // On Windows, the path of `file:///C:/foo` is `/C:/foo`.
+    let path = match path.strip_prefix('/') {
+        Some(rest) if cfg!(windows) && rest.as_bytes().get(1) == Some(&b':') => rest.to_string(),
+        _ => path,
+    };
+    Ok(PathBuf::from(path))
+}
+
+/// Decodes `%XX` escapes, returning `None` if they are malformed or don't decode to UTF-8.
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Strips `root_dir` from the start of `path`. Windows paths are compared case-insensitively,
+/// since editors and mypy may disagree on the case of the drive letter.
+fn strip_prefix(path: &Path, root_dir: &Path) -> Option<PathBuf> {
+    if let Ok(relative) = path.strip_prefix(root_dir) {
+        return Some(relative.to_path_buf());
+    }
+    if !cfg!(windows) {
+        return None;
+    }
+    let mut components = path.components();
+    for root_component in root_dir.components() {
+        let component = components.next()?;
+        if !component
+            .as_os_str()
+            .eq_ignore_ascii_case(root_component.as_os_str())
+        {
+            return None;
+        }
+    }
+    Some(components.as_path().to_path_buf())
+}
+
+/// Finds the path of `path` relative to `root_dir`. mypy may report paths through a different but
+/// equivalent root (e.g. a symlinked project directory, or `/private/var` rather than `/var` on
+/// macOS), so this falls back to comparing canonical paths, and then to the longest suffix of
+/// `path` that exists under `root_dir`.
+fn strip_root(root_dir: &Path, path: &Path) -> Option<PathBuf> {
+    if let Some(relative) = strip_prefix(path, root_dir) {
+        return Some(relative);
+    }
+    if let (Ok(root_dir), Ok(path)) = (root_dir.canonicalize(), path.canonicalize()) {
+        if let Ok(relative) = path.strip_prefix(root_dir) {
+            return Some(relative.to_path_buf());
+        }
+    }
+    let components: Vec<_> = path.components().collect();
+    (1..components.len())
+        .map(|start| components[start..].iter().collect::<PathBuf>())
+        .find(|suffix| root_dir.join(suffix).is_file())
 }
 
 impl Deref for RelPathBuf {
@@ -53,3 +206,135 @@ impl Deref for RelPathBuf {
         &self.path_buf
     }
 }
+
+#[test]
+fn test_eq_normalizes_paths() {
+    let root_dir = Path::new("/project");
+    let target = RelPathBuf::from_filename(root_dir, "foo.py").unwrap();
+    assert_eq!(
+        RelPathBuf::from_filename(root_dir, "./foo.py").unwrap(),
+        target
+    );
+    assert_eq!(
+        RelPathBuf::from_filename(Path::new("/project/"), "pkg/../foo.py").unwrap(),
+        target
+    );
+    assert_ne!(
+        RelPathBuf::from_filename(root_dir, "pkg/foo.py").unwrap(),
+        target
+    );
+}
+
+#[cfg(windows)]
+#[test]
+fn test_from_filename_drive_letter() {
+    let root_dir = Path::new(r"C:\Project");
+    let relpath = RelPathBuf::from_filename(root_dir, r"c:\project\pkg\mod.py").unwrap();
+    assert_eq!(*relpath, Path::new(r"pkg\mod.py"));
+    let uri = Url::parse("file:///c:/Project/pkg/mod.py").unwrap();
+    let relpath = RelPathBuf::from_uri(root_dir.to_path_buf(), uri).unwrap();
+    assert_eq!(*relpath, Path::new(r"pkg\mod.py"));
+}
+
+#[test]
+fn test_from_uri_decodes_path() {
+    let root_dir = PathBuf::from("/project");
+    let uri = Url::parse("file:///project/my%20pkg/caf%C3%A9.py").unwrap();
+    let relpath = RelPathBuf::from_uri(root_dir.clone(), uri).unwrap();
+    assert_eq!(*relpath, Path::new("my pkg/café.py"));
+    let uri = Url::parse("file://build-host/project/my%20pkg/mod.py").unwrap();
+    let relpath = RelPathBuf::from_uri(root_dir.clone(), uri).unwrap();
+    assert_eq!(*relpath, Path::new("my pkg/mod.py"));
+}
+
+#[test]
+fn test_from_uri_rejects_other_schemes() {
+    let uri = Url::parse("untitled:Untitled-1").unwrap();
+    let error = RelPathBuf::from_uri(PathBuf::from("/project"), uri).unwrap_err();
+    assert!(error.to_string().contains("not a file uri"));
+}
+
+#[test]
+fn test_from_uri_in_parent() {
+    let uri = Url::parse("file:///shared/utils/helpers.py").unwrap();
+    assert!(RelPathBuf::from_uri(PathBuf::from("/project"), uri.clone()).is_err());
+    let relpath = RelPathBuf::from_uri_in_parent(uri).unwrap();
+    assert_eq!(relpath.root_dir(), Path::new("/shared/utils"));
+    assert_eq!(*relpath, Path::new("helpers.py"));
+    let uri = Url::parse("untitled:Untitled-1").unwrap();
+    assert!(RelPathBuf::from_uri_in_parent(uri).is_err());
+}
+
+#[test]
+fn test_to_module() {
+    let root_dir = Path::new("/project");
+    let path = |filename| RelPathBuf::from_filename(root_dir, filename).unwrap();
+    assert_eq!(
+        path("pkg/mod.py").to_module(&[]).as_deref(),
+        Some("pkg.mod")
+    );
+    assert_eq!(
+        path("pkg/__init__.py").to_module(&[]).as_deref(),
+        Some("pkg")
+    );
+    let source_roots = [PathBuf::from("src"), PathBuf::from("/project/src/vendored")];
+    assert_eq!(
+        path("src/pkg/mod.py").to_module(&source_roots).as_deref(),
+        Some("pkg.mod")
+    );
+    assert_eq!(
+        path("src/vendored/lib/core.pyi")
+            .to_module(&source_roots)
+            .as_deref(),
+        Some("lib.core")
+    );
+    assert_eq!(
+        path("tests/test_mod.py")
+            .to_module(&source_roots)
+            .as_deref(),
+        Some("tests.test_mod")
+    );
+    assert_eq!(
+        path("srcs/mod.py").to_module(&source_roots).as_deref(),
+        Some("srcs.mod")
+    );
+}
+
+#[test]
+fn test_percent_decode() {
+    assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+    assert_eq!(percent_decode("%E2%9C%93").as_deref(), Some("✓"));
+    assert_eq!(percent_decode("%2"), None);
+    assert_eq!(percent_decode("%FF"), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_from_filename_symlinked_root() {
+    let dir = crate::test_dir("symlinked-root");
+    let project = dir.join("project");
+    std::fs::create_dir_all(project.join("pkg")).unwrap();
+    std::fs::write(project.join("pkg/mod.py"), "").unwrap();
+    let link = dir.join("link");
+    std::os::unix::fs::symlink(&project, &link).unwrap();
+    let filename = project.join("pkg/mod.py");
+    let relpath = RelPathBuf::from_filename(&link, filename.to_str().unwrap()).unwrap();
+    assert_eq!(*relpath, Path::new("pkg/mod.py"));
+    assert_eq!(relpath.absolute_path(), link.join("pkg/mod.py"));
+    let filename = link.join("pkg/mod.py");
+    let relpath = RelPathBuf::from_filename(&project, filename.to_str().unwrap()).unwrap();
+    assert_eq!(*relpath, Path::new("pkg/mod.py"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_from_filename_suffix_fallback() {
+    let dir = crate::test_dir("suffix-fallback");
+    let project = dir.join("project");
+    std::fs::create_dir_all(project.join("pkg")).unwrap();
+    std::fs::write(project.join("pkg/mod.py"), "").unwrap();
+    let relpath = RelPathBuf::from_filename(&project, "/elsewhere/checkout/pkg/mod.py").unwrap();
+    assert_eq!(*relpath, Path::new("pkg/mod.py"));
+    assert!(RelPathBuf::from_filename(&project, "/elsewhere/checkout/pkg/other.py").is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,102 @@
+use std::process::Output;
+use tokio::process::Command;
+
+/// Runs the dmypy and mypy commands built by the server. Abstracted so that tests can substitute
+/// canned output for real processes.
+#[tower_lsp::async_trait]
+pub(crate) trait CommandRunner: Send + Sync {
+    async fn run(&self, cmd: &mut Command) -> std::io::Result<Output>;
+}
+
+/// Runs commands as subprocesses.
+pub(crate) struct ProcessRunner;
+
+#[tower_lsp::async_trait]
+impl CommandRunner for ProcessRunner {
+    async fn run(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        cmd.output().await
+    }
+}
+
+/// Maps the arguments of a command to its exit code and stdout.
+#[cfg(test)]
+type Respond = dyn Fn(&[String]) -> (i32, String) + Send + Sync;
+
+/// Answers commands with a canned response, recording the arguments of each command it is asked
+/// to run.
+#[cfg(test)]
+pub(crate) struct MockRunner {
+    respond: Box<Respond>,
+    pub(crate) calls: std::sync::Arc<std::sync::Mutex<Vec<Vec<String>>>>,
+}
+
+#[cfg(test)]
+impl MockRunner {
+    pub(crate) fn new(
+        respond: impl Fn(&[String]) -> (i32, String) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            respond: Box::new(respond),
+            calls: Default::default(),
+        }
+    }
+}
+
+/// Fails to run every command, as if it couldn't be found.
+#[cfg(test)]
+pub(crate) struct FailingRunner;
+
+#[cfg(test)]
+#[tower_lsp::async_trait]
+impl CommandRunner for FailingRunner {
+    async fn run(&self, _: &mut Command) -> std::io::Result<Output> {
+        Err(std::io::ErrorKind::NotFound.into())
+    }
+}
+
+#[cfg(test)]
+fn exit_status(code: i32) -> std::process::ExitStatus {
+    #[cfg(unix)]
+    let status = std::os::unix::process::ExitStatusExt::from_raw(code << 8);
+    #[cfg(windows)]
+    let status = std::os::windows::process::ExitStatusExt::from_raw(code as u32);
+    status
+}
+
+#[cfg(test)]
+#[tower_lsp::async_trait]
+impl CommandRunner for MockRunner {
+    async fn run(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        let args: Vec<String> = cmd
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let (code, stdout) = (self.respond)(&args);
+        self.calls.lock().unwrap().push(args);
+        Ok(Output {
+            status: exit_status(code),
+            stdout: stdout.into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_mock_runner() {
+    let runner = MockRunner::new(|args| match args.last().map(String::as_str) {
+        Some("status") => (0, "Daemon is up and running\n".to_string()),
+        _ => (2, String::new()),
+    });
+    let output = runner
+        .run(Command::new("dmypy").arg("status"))
+        .await
+        .unwrap();
+    assert!(output.status.success());
+    let output = runner.run(Command::new("dmypy").arg("stop")).await.unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        *runner.calls.lock().unwrap(),
+        [vec!["status".to_string()], vec!["stop".to_string()]]
+    );
+}
@@ -0,0 +1,229 @@
+use regex::Regex;
+use std::path::Path;
+
+/// A signature suggested by `dmypy suggest`, e.g. `(int, *str) -> bool`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Suggestion {
+    pub(crate) arg_types: Vec<String>,
+    pub(crate) return_type: String,
+}
+
+/// Splits on commas that aren't nested inside brackets or parentheses.
+fn split_top_level(text: &str) -> Vec<&str> {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut terms = Vec::new();
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                terms.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    terms.push(&text[start..]);
+    terms
+        .into_iter()
+        .map(str::trim)
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+/// Finds the index of the parenthesis closing the one at `open`.
+fn matching_paren(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in text[open..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses the first line of `dmypy suggest` output.
+pub(crate) fn parse_suggestion(output: &str) -> Option<Suggestion> {
+    let line = output.lines().next()?.trim();
+    if !line.starts_with('(') {
+        return None;
+    }
+    let close = matching_paren(line, 0)?;
+    let return_type = line[close + 1..].trim().strip_prefix("->")?.trim();
+    Some(Suggestion {
+        arg_types: split_top_level(&line[1..close])
+            .into_iter()
+            .map(|arg_type| arg_type.trim_start_matches('*').to_string())
+            .collect(),
+        return_type: return_type.to_string(),
+    })
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Finds the dotted qualified name (e.g. `Outer.Inner.method`) of the function defined on
+/// `line_number`, by walking up through enclosing, less-indented `class` and `def` lines.
+/// Returns the name and whether the function is a method.
+pub(crate) fn function_qualname(text: &str, line_number: usize) -> Option<(String, bool)> {
+    let re = Regex::new(r"^\s*(?:async\s+)?(def|class)\s+(\w+)").unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    let def_line = lines.get(line_number)?;
+    let caps = re.captures(def_line).filter(|caps| &caps[1] == "def")?;
+    let mut names = vec![caps[2].to_string()];
+    let mut is_method = false;
+    let mut indent = indentation(def_line);
+    for line in lines[..line_number].iter().rev() {
+        if indent == 0 {
+            break;
+        }
+        if line.trim().is_empty() || indentation(line) >= indent {
+            continue;
+        }
+        indent = indentation(line);
+        let Some(caps) = re.captures(line) else {
+            continue;
+        };
+        if names.len() == 1 {
+            is_method = &caps[1] == "class";
+        }
+        names.push(caps[2].to_string());
+    }
+    names.reverse();
+    Some((names.join("."), is_method))
+}
+
+/// Maps a path relative to the project root to a dotted module name.
+pub(crate) fn module_name(path: &Path) -> Option<String> {
+    let path = path.with_extension("");
+    let mut components: Vec<String> = path
+        .components()
+        .map(|component| component.as_os_str().to_str().map(str::to_string))
+        .collect::<Option<_>>()?;
+    if components.last().is_some_and(|last| last == "__init__") {
+        components.pop();
+    }
+    (!components.is_empty()).then(|| components.join("."))
+}
+
+/// Annotates a single-line `def` with a suggested signature. Parameters and return types that
+/// are already annotated are left alone. Returns `None` if the signature can't be handled (e.g.
+/// it spans multiple lines) or nothing would change.
+pub(crate) fn annotate_def_line(
+    line: &str,
+    suggestion: &Suggestion,
+    is_method: bool,
+) -> Option<String> {
+    let open = line.find('(')?;
+    let close = matching_paren(line, open)?;
+    let mut params: Vec<&str> = split_top_level(&line[open + 1..close]);
+    let mut annotated: Vec<String> = Vec::new();
+    if is_method && !params.is_empty() {
+        annotated.push(params.remove(0).to_string());
+    }
+    let mut arg_types = suggestion.arg_types.iter();
+    for param in params {
+        if param == "*" || param == "/" {
+            annotated.push(param.to_string());
+            continue;
+        }
+        let Some(arg_type) = arg_types.next() else {
+            annotated.push(param.to_string());
+            continue;
+        };
+        let (name, default) = match param.split_once('=') {
+            Some((name, default)) => (name.trim(), Some(default.trim())),
+            None => (param, None),
+        };
+        if name.contains(':') {
+            annotated.push(param.to_string());
+            continue;
+        }
+        annotated.push(match default {
+            Some(default) => format!("{name}: {arg_type} = {default}"),
+            None => format!("{name}: {arg_type}"),
+        });
+    }
+    let rest = &line[close + 1..];
+    let rest = if rest.trim_start().starts_with("->") {
+        rest.to_string()
+    } else {
+        format!(" -> {}{}", suggestion.return_type, rest)
+    };
+    let new_line = format!("{}({}){}", &line[..open], annotated.join(", "), rest);
+    (new_line != line).then_some(new_line)
+}
+
+#[test]
+fn test_parse_suggestion() {
+    assert_eq!(
+        parse_suggestion("(int, Dict[str, int], *str) -> Optional[bool]\n"),
+        Some(Suggestion {
+            arg_types: vec![
+                "int".to_string(),
+                "Dict[str, int]".to_string(),
+                "str".to_string()
+            ],
+            return_type: "Optional[bool]".to_string(),
+        })
+    );
+    assert_eq!(parse_suggestion("No guesses"), None);
+}
+
+#[test]
+fn test_function_qualname() {
+    let text = "class Outer:\n    class Inner:\n        def method(self, x):\n            pass\n\n    def other(self):\n        def local():\n            pass\n\ndef top(x):\n    pass\n";
+    assert_eq!(
+        function_qualname(text, 2),
+        Some(("Outer.Inner.method".to_string(), true))
+    );
+    assert_eq!(
+        function_qualname(text, 5),
+        Some(("Outer.other".to_string(), true))
+    );
+    assert_eq!(
+        function_qualname(text, 6),
+        Some(("Outer.other.local".to_string(), false))
+    );
+    assert_eq!(function_qualname(text, 9), Some(("top".to_string(), false)));
+    assert_eq!(function_qualname(text, 0), None);
+}
+
+#[test]
+fn test_module_name() {
+    assert_eq!(
+        module_name(Path::new("pkg/mod.py")).as_deref(),
+        Some("pkg.mod")
+    );
+    assert_eq!(
+        module_name(Path::new("pkg/__init__.py")).as_deref(),
+        Some("pkg")
+    );
+}
+
+#[test]
+fn test_annotate_def_line() {
+    let suggestion = parse_suggestion("(int, str) -> bool").unwrap();
+    assert_eq!(
+        annotate_def_line("def f(x, y=\"a\"):", &suggestion, false).as_deref(),
+        Some("def f(x: int, y: str = \"a\") -> bool:")
+    );
+    assert_eq!(
+        annotate_def_line("    async def m(self, x, *args):", &suggestion, true).as_deref(),
+        Some("    async def m(self, x: int, *args: str) -> bool:")
+    );
+    assert_eq!(
+        annotate_def_line("def f(x: int, y: str) -> bool:", &suggestion, false),
+        None
+    );
+    assert_eq!(annotate_def_line("def f(", &suggestion, false), None);
+}
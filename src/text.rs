@@ -0,0 +1,339 @@
+use regex::Regex;
+use tower_lsp::lsp_types::{Position, Range};
+
+/// Replaces `\r\n` and lone `\r` line endings (both of which LSP treats as line breaks) with
+/// `\n`. Document text is stored in this form, so that line and column math never sees a `\r`.
+pub(crate) fn normalize_line_endings(text: &str) -> String {
+    if !text.contains('\r') {
+        return text.to_string();
+    }
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Removes ANSI escape sequences, such as the colors mypy adds with `--color-output`, which would
+/// otherwise keep its output from being parsed.
+pub(crate) fn strip_ansi_escapes(text: &str) -> std::borrow::Cow<'_, str> {
+    let re = Regex::new(r"\x1b(?:\[[0-9;?]*[ -/]*[@-~]|[()][0-9A-Za-z])").unwrap();
+    re.replace_all(text, "")
+}
+
+/// Keeps a range (counting characters, as mypy does) within `text` and at least one character wide
+/// where the line allows it. mypy may place e.g. a syntax error past the end of the file or at
+/// the end of a line, and some editors don't render zero-width ranges.
+pub(crate) fn clamp_range(text: &str, range: Range) -> Range {
+    let lines: Vec<&str> = text.lines().collect();
+    let Some(last_line) = lines.len().checked_sub(1) else {
+        return Range::default();
+    };
+    let line_length = |line: u32| lines[line as usize].chars().count() as u32;
+    let clamp = |position: Position| {
+        if position.line as usize > last_line {
+            let line = last_line as u32;
+            return Position {
+                line,
+                character: line_length(line),
+            };
+        }
+        Position {
+            line: position.line,
+            character: position.character.min(line_length(position.line)),
+        }
+    };
+    let mut start = clamp(range.start);
+    let mut end = clamp(range.end);
+    if end <= start {
+        let length = line_length(start.line);
+        if start.character < length {
+            end = Position {
+                line: start.line,
+                character: start.character + 1,
+            };
+        } else {
+            start.character = length.saturating_sub(1);
+            end = Position {
+                line: start.line,
+                character: length,
+            };
+        }
+    }
+    Range { start, end }
+}
+
+/// Moves the end of a range (counting characters) back past any whitespace before it, onto an
+/// earlier line if need be. A range that would become empty is left as it is.
+pub(crate) fn trim_range_end(text: &str, range: Range) -> Range {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut line = range.end.line;
+    let mut character = range.end.character;
+    let end = loop {
+        let Some(content) = lines.get(line as usize) else {
+            return range;
+        };
+        let prefix: String = content.chars().take(character as usize).collect();
+        let trimmed = prefix.trim_end().chars().count() as u32;
+        if trimmed > 0 || line <= range.start.line {
+            break Position {
+                line,
+                character: trimmed,
+            };
+        }
+        line -= 1;
+        character = u32::MAX;
+    };
+    match end > range.start {
+        true => Range {
+            start: range.start,
+            end,
+        },
+        false => range,
+    }
+}
+
+/// Converts a position whose `character` counts Unicode scalar values (as mypy reports columns)
+/// into one that counts UTF-16 code units (as LSP expects by default). Positions beyond the end
+/// of the line keep their excess columns.
+pub(crate) fn to_utf16_position(text: &str, position: Position) -> Position {
+    let Some(line) = text.lines().nth(position.line as usize) else {
+        return position;
+    };
+    let mut chars = line.chars();
+    let mut character = 0;
+    for _ in 0..position.character {
+        match chars.next() {
+            Some(c) => character += c.len_utf16() as u32,
+            None => character += 1,
+        }
+    }
+    Position {
+        line: position.line,
+        character,
+    }
+}
+
+pub(crate) fn to_utf16_range(text: &str, range: Range) -> Range {
+    Range {
+        start: to_utf16_position(text, range.start),
+        end: to_utf16_position(text, range.end),
+    }
+}
+
+/// The inverse of `to_utf16_position`: converts a position from the client, counting UTF-16 code
+/// units, into one counting Unicode scalar values, as `dmypy inspect` expects. A position inside
+/// a surrogate pair moves back to the start of its character.
+pub(crate) fn from_utf16_position(text: &str, position: Position) -> Position {
+    let Some(line) = text.lines().nth(position.line as usize) else {
+        return position;
+    };
+    let mut units = 0;
+    let mut character = 0;
+    for c in line.chars() {
+        if units + c.len_utf16() as u32 > position.character {
+            break;
+        }
+        units += c.len_utf16() as u32;
+        character += 1;
+    }
+    // Past the end of the line, the excess columns are kept as they are.
+    if units == line.encode_utf16().count() as u32 {
+        character += position.character - units;
+    }
+    Position {
+        line: position.line,
+        character,
+    }
+}
+
+#[test]
+fn test_to_utf16_position_ascii() {
+    let position = Position {
+        line: 1,
+        character: 4,
+    };
+    assert_eq!(to_utf16_position("x = 1\ny = 2\n", position), position);
+}
+
+#[test]
+fn test_to_utf16_position_emoji() {
+    // "🐍" is one character but two UTF-16 code units.
+    let text = "import os\nname = \"🐍\" + 1\n";
+    let position = Position {
+        line: 1,
+        character: 11,
+    };
+    assert_eq!(
+        to_utf16_position(text, position),
+        Position {
+            line: 1,
+            character: 12,
+        }
+    );
+}
+
+#[test]
+fn test_to_utf16_position_accented() {
+    // "é" is a single UTF-16 code unit, so columns are unchanged.
+    let text = "café = 1 + \"\"\n";
+    let range = Range {
+        start: Position {
+            line: 0,
+            character: 7,
+        },
+        end: Position {
+            line: 0,
+            character: 13,
+        },
+    };
+    assert_eq!(to_utf16_range(text, range), range);
+}
+
+#[test]
+fn test_from_utf16_position() {
+    let text = "import os\nname = \"🐍\" + 1\n";
+    let position = |character| Position { line: 1, character };
+    assert_eq!(from_utf16_position(text, position(12)), position(11));
+    assert_eq!(from_utf16_position(text, position(5)), position(5));
+    // The middle of the surrogate pair belongs to the snake.
+    assert_eq!(from_utf16_position(text, position(9)), position(8));
+    assert_eq!(from_utf16_position(text, position(18)), position(17));
+    for character in 0..18 {
+        let char_position = from_utf16_position(text, position(character));
+        if character != 9 {
+            assert_eq!(to_utf16_position(text, char_position), position(character));
+        }
+    }
+}
+
+#[test]
+fn test_to_utf16_position_past_end_of_line() {
+    let text = "🐍\n";
+    let position = Position {
+        line: 0,
+        character: 3,
+    };
+    assert_eq!(
+        to_utf16_position(text, position),
+        Position {
+            line: 0,
+            character: 4,
+        }
+    );
+}
+
+#[test]
+fn test_clamp_range() {
+    let text = "x = (\n    1,\n";
+    let range = |start: (u32, u32), end: (u32, u32)| Range {
+        start: Position {
+            line: start.0,
+            character: start.1,
+        },
+        end: Position {
+            line: end.0,
+            character: end.1,
+        },
+    };
+    // A syntax error reported past the end of the file.
+    assert_eq!(
+        clamp_range(text, range((2, 0), (2, 0))),
+        range((1, 5), (1, 6))
+    );
+    // A zero-width error at column 0.
+    assert_eq!(
+        clamp_range(text, range((0, 0), (0, 0))),
+        range((0, 0), (0, 1))
+    );
+    // A zero-width error at the end of a line.
+    assert_eq!(
+        clamp_range(text, range((0, 5), (0, 5))),
+        range((0, 4), (0, 5))
+    );
+    assert_eq!(
+        clamp_range(text, range((0, 2), (1, 9))),
+        range((0, 2), (1, 6))
+    );
+    assert_eq!(clamp_range("", range((3, 1), (3, 1))), Range::default());
+}
+
+#[test]
+fn test_trim_range_end() {
+    let text = "x = foo(1)   \n    \ny = 2\n";
+    let range = |start: (u32, u32), end: (u32, u32)| Range {
+        start: Position {
+            line: start.0,
+            character: start.1,
+        },
+        end: Position {
+            line: end.0,
+            character: end.1,
+        },
+    };
+    assert_eq!(
+        trim_range_end(text, range((0, 4), (0, 13))),
+        range((0, 4), (0, 10))
+    );
+    assert_eq!(
+        trim_range_end(text, range((0, 4), (0, 10))),
+        range((0, 4), (0, 10))
+    );
+    // A range made up of whitespace only is kept.
+    assert_eq!(
+        trim_range_end(text, range((0, 10), (0, 13))),
+        range((0, 10), (0, 13))
+    );
+    assert_eq!(
+        trim_range_end(text, range((1, 0), (1, 4))),
+        range((1, 0), (1, 4))
+    );
+    assert_eq!(
+        trim_range_end(text, range((0, 4), (1, 4))),
+        range((0, 4), (0, 10))
+    );
+    assert_eq!(
+        trim_range_end(text, range((0, 4), (2, 3))),
+        range((0, 4), (2, 3))
+    );
+}
+
+#[test]
+fn test_strip_ansi_escapes() {
+    assert_eq!(
+        strip_ansi_escapes("\x1b[1m\x1b[31merror:\x1b(B\x1b[m x"),
+        "error: x"
+    );
+    assert_eq!(strip_ansi_escapes("plain: text"), "plain: text");
+}
+
+#[test]
+fn test_normalize_line_endings() {
+    assert_eq!(normalize_line_endings("a\r\nb\rc\n"), "a\nb\nc\n");
+    assert_eq!(normalize_line_endings("a\nb"), "a\nb");
+}
+
+#[test]
+fn test_to_utf16_range_crlf() {
+    let text = normalize_line_endings("import os\r\nname = \"🐍\" + 1\r\nx = 2\r\n");
+    let range = Range {
+        start: Position {
+            line: 1,
+            character: 7,
+        },
+        end: Position {
+            line: 1,
+            character: 14,
+        },
+    };
+    assert_eq!(
+        to_utf16_range(&text, range),
+        Range {
+            start: Position {
+                line: 1,
+                character: 7,
+            },
+            end: Position {
+                line: 1,
+                character: 15,
+            },
+        }
+    );
+    assert_eq!(text.lines().nth(2), Some("x = 2"));
+}
@@ -0,0 +1,87 @@
+use regex::Regex;
+
+/// Finds the byte offset of the `#` starting a comment on a line of Python, skipping over string
+/// literals on that line.
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == '#' => return Some(index),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Rewrites `line` so that mypy ignores errors with the given `code` on it. An existing
+/// `# type: ignore[...]` comment is extended rather than duplicated, and any other trailing
+/// comment is kept after the new ignore comment. Returns `None` if the line already ignores
+/// `code`.
+pub(crate) fn add_type_ignore(line: &str, code: &str) -> Option<String> {
+    let Some(start) = comment_start(line) else {
+        return Some(format!("{}  # type: ignore[{code}]", line.trim_end()));
+    };
+    let re = Regex::new(r"^#\s*type:\s*ignore(\[(?P<codes>[^\]]*)\])?").unwrap();
+    let Some(caps) = re.captures(&line[start..]) else {
+        return Some(format!(
+            "{}# type: ignore[{code}]  {}",
+            &line[..start],
+            &line[start..]
+        ));
+    };
+    // A bare `# type: ignore` already ignores every code.
+    let codes = caps.name("codes")?;
+    if codes
+        .as_str()
+        .split(',')
+        .any(|existing| existing.trim() == code)
+    {
+        return None;
+    }
+    let end = start + codes.end();
+    let separator = if codes.as_str().trim().is_empty() {
+        ""
+    } else {
+        ", "
+    };
+    Some(format!("{}{separator}{code}{}", &line[..end], &line[end..]))
+}
+
+#[test]
+fn test_add_type_ignore() {
+    assert_eq!(
+        add_type_ignore("x: int = \"a\"", "assignment").as_deref(),
+        Some("x: int = \"a\"  # type: ignore[assignment]")
+    );
+    assert_eq!(
+        add_type_ignore("x: int = \"#\"  ", "assignment").as_deref(),
+        Some("x: int = \"#\"  # type: ignore[assignment]")
+    );
+}
+
+#[test]
+fn test_add_type_ignore_preserves_comment() {
+    assert_eq!(
+        add_type_ignore("x: int = \"a\"  # why not", "assignment").as_deref(),
+        Some("x: int = \"a\"  # type: ignore[assignment]  # why not")
+    );
+}
+
+#[test]
+fn test_add_type_ignore_existing() {
+    assert_eq!(
+        add_type_ignore("f(x)  # type: ignore[arg-type]  # note", "call-arg").as_deref(),
+        Some("f(x)  # type: ignore[arg-type, call-arg]  # note")
+    );
+    assert_eq!(
+        add_type_ignore("f(x)  # type: ignore[arg-type]", "arg-type"),
+        None
+    );
+    assert_eq!(add_type_ignore("f(x)  # type: ignore", "arg-type"), None);
+}
@@ -0,0 +1,69 @@
+use regex::Regex;
+
+/// A mypy release, as reported by `dmypy --version` or `mypy --version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct MypyVersion {
+    pub(crate) major: u32,
+    pub(crate) minor: u32,
+    pub(crate) patch: u32,
+}
+
+impl std::fmt::Display for MypyVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl MypyVersion {
+    /// Parses e.g. `dmypy 1.11.2 (compiled: yes)`. A missing patch number is treated as 0.
+    pub(crate) fn parse(output: &str) -> Option<MypyVersion> {
+        let re = Regex::new(r"\b(\d+)\.(\d+)(?:\.(\d+))?").unwrap();
+        let caps = re.captures(output)?;
+        Some(MypyVersion {
+            major: caps[1].parse().ok()?,
+            minor: caps[2].parse().ok()?,
+            patch: caps
+                .get(3)
+                .map_or(Some(0), |patch| patch.as_str().parse().ok())?,
+        })
+    }
+
+    /// `--show-error-end` was added in mypy 0.981.
+    pub(crate) fn supports_show_error_end(&self) -> bool {
+        *self
+            >= MypyVersion {
+                major: 0,
+                minor: 981,
+                patch: 0,
+            }
+    }
+}
+
+#[test]
+fn test_parse_mypy_version() {
+    assert_eq!(
+        MypyVersion::parse("dmypy 1.11.2 (compiled: yes)\n"),
+        Some(MypyVersion {
+            major: 1,
+            minor: 11,
+            patch: 2
+        })
+    );
+    assert_eq!(
+        MypyVersion::parse("mypy 0.971 (compiled: no)"),
+        Some(MypyVersion {
+            major: 0,
+            minor: 971,
+            patch: 0
+        })
+    );
+    assert_eq!(MypyVersion::parse("dmypy: command not found"), None);
+}
+
+#[test]
+fn test_supports_show_error_end() {
+    let version = |text| MypyVersion::parse(text).unwrap();
+    assert!(!version("mypy 0.971").supports_show_error_end());
+    assert!(version("mypy 0.981").supports_show_error_end());
+    assert!(version("dmypy 1.4.1").supports_show_error_end());
+}